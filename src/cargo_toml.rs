@@ -1,5 +1,5 @@
 use std::collections::BTreeMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use cu::pre::*;
 
@@ -21,13 +21,30 @@ pub struct CargoManifestInfo {
     pub resolved_dependencies: Option<toml::Table>,
     /// The [build-dependencies] section of the Cargo.toml
     pub resolved_build_dependencies: Option<toml::Table>,
-    /// The [target] section of the Cargo.toml
+    /// The [target] section of the Cargo.toml, with only the
+    /// [dependencies] and [build-dependencies] of each target
     pub resolved_target: Option<toml::Table>,
     /// [features] section of the Cargo.toml,
     ///
     /// key is the feature name, value are the dep:* features
     pub dep_features: BTreeMap<String, Vec<String>>,
     pub default_features: Vec<String>,
+    /// Path to the Cargo.lock of the workspace (or the package if not in a workspace),
+    /// if it exists
+    pub lockfile_path: Option<PathBuf>,
+    /// The dependency resolver used by the crate when building normally
+    pub resolver: String,
+    /// The [lints] section of the Cargo.toml, including the ones inherited from workspace
+    pub resolved_lints: Option<toml::Table>,
+    /// Edition of the generated test package, which is the edition of the package
+    /// by default, or 2015 if not specified (same as cargo)
+    pub test_edition: String,
+    /// If the crate is added to the test package with its default features,
+    /// instead of `default-features = false`
+    pub test_default_features: bool,
+    /// The [patch] and [replace] sections of the workspace root Cargo.toml
+    /// (or the package if not in a workspace), with paths resolved
+    pub resolved_patch: toml::Table,
 }
 
 pub fn manifest_has_workspace(manifest_path: &Path) -> bool {
@@ -50,7 +67,16 @@ pub fn manifest_has_workspace(manifest_path: &Path) -> bool {
     cargo_toml.get("workspace").is_some()
 }
 
-pub fn prepare(manifest_path: &Path) -> cu::Result<CargoManifestInfo> {
+/// Read and prepare the Cargo.toml at the manifest path.
+///
+/// When looking for the workspace the package is in, parent directories
+/// outside of `workspace_root` (if specified) are not searched.
+/// If `entry` is specified, it is used as the lib entrypoint instead of `lib.path`
+pub fn prepare(
+    manifest_path: &Path,
+    workspace_root: Option<&Path>,
+    entry: Option<&str>,
+) -> cu::Result<CargoManifestInfo> {
     cu::debug!("reading Cargo.toml at {}", manifest_path.display());
     let manifest_path_abs = cu::check!(
         manifest_path.normalize_exists(),
@@ -80,15 +106,39 @@ pub fn prepare(manifest_path: &Path) -> cu::Result<CargoManifestInfo> {
     cu::debug!("package name: {package_name}");
 
     cu::debug!("finding lib entrypoint");
-    let lib_entrypoint = match cargo_toml.get("lib") {
-        Some(lib) => {
+    let lib_entrypoint = match (entry, cargo_toml.get_mut("lib")) {
+        (Some(entry), lib) => {
+            cu::debug!("using crate.entry from Layerfile as lib entrypoint");
+            // the entry file is built as the library in the generated package
+            let path = toml::Value::String(entry.to_string());
+            match lib {
+                Some(lib) => {
+                    let lib = cu::check!(lib.as_table_mut(), "lib in Cargo.toml is not a table")?;
+                    lib.insert("path".to_string(), path);
+                }
+                None => {
+                    let mut lib = toml::Table::new();
+                    lib.insert("path".to_string(), path);
+                    cargo_toml.insert("lib".to_string(), toml::Value::Table(lib));
+                }
+            }
+            // the entry file might be the binary, which should not be built as well
+            if let Some(package) = cargo_toml.get_mut("package").and_then(|x| x.as_table_mut()) {
+                package.insert("autobins".to_string(), toml::Value::Boolean(false));
+            }
+            if let Some(bins) = cargo_toml.get_mut("bin").and_then(|x| x.as_array_mut()) {
+                bins.retain(|bin| bin.get("path").and_then(|x| x.as_str()) != Some(entry));
+            }
+            entry.to_string()
+        }
+        (None, Some(lib)) => {
             let lib_entrypoint = cu::check!(
                 lib.get("path").and_then(|p| p.as_str()),
                 "failed to read lib.path from Cargo.toml"
             )?;
             lib_entrypoint.to_string()
         }
-        None => {
+        (None, None) => {
             cu::debug!("no lib section found in Cargo.toml, assuming default src/lib.rs");
             "src/lib.rs".to_string()
         }
@@ -105,11 +155,8 @@ pub fn prepare(manifest_path: &Path) -> cu::Result<CargoManifestInfo> {
     // this is because we are not changing the content in Cargo.toml,
     // just copying the entry point file from the original location
     // to the temporary directory
-    if actual_lib_path.is_absolute() {
-        cu::error!(
-            "lib entry point path is absolute: {}",
-            actual_lib_path.display()
-        );
+    if Path::new(&lib_entrypoint).is_absolute() {
+        cu::error!("lib entry point path is absolute: {lib_entrypoint}");
         cu::warn!("absolute lib entry point path is not supported right now.");
         cu::hint!(
             "this is because we need to generate a modified entry point at the same relative path as the original crate."
@@ -121,70 +168,133 @@ pub fn prepare(manifest_path: &Path) -> cu::Result<CargoManifestInfo> {
     }
 
     cu::debug!("checking if we are in a workspace");
-    let workspace_deps = if let Some(workspace) = cargo_toml.get_mut("workspace") {
-        cu::debug!("found workspace section in Cargo.toml");
-        cu::check!(
-            resolve_paths_in_workspace(workspace, &manifest_dir_abs),
-            "failed to resolve paths in workspace section"
-        )?;
-        workspace
-            .get("dependencies")
-            .and_then(|deps| deps.as_table())
-            .cloned()
-    } else {
-        cu::debug!("traversing up the directories to find workspace");
-        // traverse up the directory tree to find a Cargo.toml with a [workspace] section
-        let parent_parent = manifest_dir_abs.parent_abs().ok();
-        let mut current_path = parent_parent.as_deref();
-        let mut workspace_deps_out = None;
-        while let Some(current) = current_path {
-            cu::trace!("checking directory for workspace: {}", current.display());
-            let workspace_manifest_path = current.join("Cargo.toml");
-            if !workspace_manifest_path.exists() {
-                cu::trace!("no Cargo.toml found in {}, skipping", current.display());
-                current_path = current.parent();
-                continue;
-            }
-            let mut workspace_toml = match cu::fs::read_string(&workspace_manifest_path)
-                .and_then(|x| toml::parse::<toml::Table>(&x))
-            {
-                Ok(table) => table,
-                Err(e) => {
-                    cu::error!(
-                        "failed to parse Cargo.toml at {}: {e}, will skip this one",
-                        workspace_manifest_path.display()
+    // the root directory of the workspace (the package directory if not in a workspace),
+    // the [workspace] section of the workspace Cargo.toml, and the [patch] and [replace]
+    // sections and the resolver of the workspace Cargo.toml (both `None` if not in a workspace,
+    // or if the package is the workspace root)
+    let (workspace_root, workspace, workspace_patch, workspace_resolver) =
+        if let Some(workspace) = cargo_toml.get_mut("workspace") {
+            cu::debug!("found workspace section in Cargo.toml");
+            cu::check!(
+                resolve_paths_in_workspace(workspace, &manifest_dir_abs),
+                "failed to resolve paths in workspace section"
+            )?;
+            (
+                manifest_dir_abs.clone(),
+                workspace.as_table().cloned(),
+                None,
+                None,
+            )
+        } else {
+            cu::debug!("traversing up the directories to find workspace");
+            // traverse up the directory tree to find a Cargo.toml with a [workspace] section
+            let parent_parent = manifest_dir_abs.parent_abs().ok();
+            let mut current_path = parent_parent.as_deref();
+            let mut workspace_out = (manifest_dir_abs.clone(), None, None, None);
+            let workspace_root = match workspace_root {
+                Some(root) => Some(cu::check!(
+                    root.normalize_exists(),
+                    "failed to find workspace root '{}'",
+                    root.display()
+                )?),
+                None => None,
+            };
+            while let Some(current) = current_path {
+                if let Some(root) = &workspace_root
+                    && !current.starts_with(root)
+                {
+                    cu::debug!(
+                        "stopping at workspace root {}, no workspace found",
+                        root.display()
                     );
+                    break;
+                }
+                cu::trace!("checking directory for workspace: {}", current.display());
+                let workspace_manifest_path = current.join("Cargo.toml");
+                if !workspace_manifest_path.exists() {
+                    cu::trace!("no Cargo.toml found in {}, skipping", current.display());
                     current_path = current.parent();
                     continue;
                 }
-            };
-            if let Some(workspace_table) = workspace_toml.get_mut("workspace") {
-                cu::debug!(
-                    "found workspace section in Cargo.toml at {}",
-                    workspace_manifest_path.display()
-                );
-                cu::check!(
-                    resolve_paths_in_workspace(workspace_table, current),
-                    "failed to resolve paths in workspace section"
-                )?;
-                cu::debug!("getting workspace dependencies");
-                workspace_deps_out = workspace_table
-                    .get("dependencies")
-                    .and_then(|deps| deps.as_table())
-                    .cloned();
-                break;
-            } else {
-                cu::trace!(
-                    "no workspace section found in Cargo.toml at {}, continuing search",
-                    workspace_manifest_path.display()
-                );
-                current_path = current.parent();
+                let mut workspace_toml = match cu::fs::read_string(&workspace_manifest_path)
+                    .and_then(|x| toml::parse::<toml::Table>(&x))
+                {
+                    Ok(table) => table,
+                    Err(e) => {
+                        cu::error!(
+                            "failed to parse Cargo.toml at {}: {e}, will skip this one",
+                            workspace_manifest_path.display()
+                        );
+                        current_path = current.parent();
+                        continue;
+                    }
+                };
+                if let Some(workspace_table) = workspace_toml.get_mut("workspace") {
+                    cu::debug!(
+                        "found workspace section in Cargo.toml at {}",
+                        workspace_manifest_path.display()
+                    );
+                    cu::check!(
+                        resolve_paths_in_workspace(workspace_table, current),
+                        "failed to resolve paths in workspace section"
+                    )?;
+                    let workspace_table = workspace_table.as_table().cloned();
+                    let patch = cu::check!(
+                        resolve_patch_sections(&workspace_toml, current),
+                        "failed to resolve paths in [patch] of workspace"
+                    )?;
+                    let resolver = detect_resolver(&workspace_toml);
+                    workspace_out = (
+                        current.to_path_buf(),
+                        workspace_table,
+                        Some(patch),
+                        Some(resolver),
+                    );
+                    break;
+                } else {
+                    cu::trace!(
+                        "no workspace section found in Cargo.toml at {}, continuing search",
+                        workspace_manifest_path.display()
+                    );
+                    current_path = current.parent();
+                }
             }
-        }
-        workspace_deps_out
-    };
+            workspace_out
+        };
+    cu::debug!("getting workspace dependencies");
+    let workspace_deps = workspace
+        .as_ref()
+        .and_then(|workspace| workspace.get("dependencies"))
+        .and_then(|deps| deps.as_table())
+        .cloned();
     cu::debug!("workspace dependencies: {:#?}", workspace_deps);
 
+    cu::debug!("resolving package fields inherited from workspace");
+    cu::check!(
+        resolve_inherited_package_fields(&mut cargo_toml, workspace.as_ref(), &workspace_root),
+        "failed to resolve package fields inherited from workspace"
+    )?;
+
+    cu::debug!("resolving lints inherited from workspace");
+    let inherits_lints = cargo_toml
+        .get("lints")
+        .and_then(|lints| lints.get("workspace"))
+        .and_then(|x| x.as_bool())
+        .unwrap_or_default();
+    if inherits_lints {
+        let lints = cu::check!(
+            workspace
+                .as_ref()
+                .and_then(|workspace| workspace.get("lints")),
+            "lints are inherited from workspace, but workspace.lints is not found"
+        )?;
+        cargo_toml.insert("lints".to_string(), lints.clone());
+    }
+    let resolved_lints = cargo_toml
+        .get("lints")
+        .and_then(|lints| lints.as_table())
+        .cloned();
+
     cu::debug!("resolving dependency paths in Cargo.toml");
     cu::check!(
         resolve_dependency_paths(&mut cargo_toml, &manifest_dir_abs, workspace_deps.as_ref()),
@@ -208,6 +318,23 @@ pub fn prepare(manifest_path: &Path) -> cu::Result<CargoManifestInfo> {
     }
     cu::debug!("finished resolving dependency paths in Cargo.toml");
 
+    // [patch] and [replace] only apply in the root of the workspace, so they are
+    // moved to the generated workspace to resolve dependencies the same way as the crate
+    let resolved_patch = match workspace_patch {
+        Some(patch) => patch,
+        None => cu::check!(
+            resolve_patch_sections(&cargo_toml, &manifest_dir_abs),
+            "failed to resolve paths in [patch] of Cargo.toml"
+        )?,
+    };
+    if !cargo_toml.contains_key("workspace") {
+        for key in ["patch", "replace"] {
+            if cargo_toml.remove(key).is_some() {
+                cu::debug!("moving [{key}] of the package to the generated workspace");
+            }
+        }
+    }
+
     let resolved_dependencies = cargo_toml
         .get("dependencies")
         .and_then(|deps| deps.as_table())
@@ -216,10 +343,7 @@ pub fn prepare(manifest_path: &Path) -> cu::Result<CargoManifestInfo> {
         .get("build-dependencies")
         .and_then(|deps| deps.as_table())
         .cloned();
-    let resolved_target = cargo_toml
-        .get("target")
-        .and_then(|target| target.as_table())
-        .cloned();
+    let resolved_target = resolve_target_dependencies(&cargo_toml);
 
     cu::debug!("extracting features from Cargo.toml");
     let feature_table = cargo_toml.get("features").and_then(|f| f.as_table());
@@ -230,15 +354,15 @@ pub fn prepare(manifest_path: &Path) -> cu::Result<CargoManifestInfo> {
                 let mut dep_features_list = Vec::new();
                 if let Some(deps) = fvalue.as_array() {
                     for dep in deps {
-                        if let Some(dep_str) = dep.as_str() {
-                            if dep_str.starts_with("dep:") {
-                                cu::trace!(
-                                    "found dependency feature: {} in feature '{}'",
-                                    dep_str,
-                                    fname
-                                );
-                                dep_features_list.push(dep_str.to_string());
-                            }
+                        if let Some(dep_str) = dep.as_str()
+                            && dep_str.starts_with("dep:")
+                        {
+                            cu::trace!(
+                                "found dependency feature: {} in feature '{}'",
+                                dep_str,
+                                fname
+                            );
+                            dep_features_list.push(dep_str.to_string());
                         }
                     }
                 } else {
@@ -270,6 +394,24 @@ pub fn prepare(manifest_path: &Path) -> cu::Result<CargoManifestInfo> {
     )?;
     cu::trace!("modified Cargo.toml content: {content}");
 
+    let test_edition = detect_edition(&cargo_toml, workspace.as_ref())
+        .unwrap_or("2015")
+        .to_string();
+    cu::debug!("edition of test package: {test_edition}");
+
+    cu::debug!("detecting dependency resolver");
+    let resolver = workspace_resolver.unwrap_or_else(|| detect_resolver(&cargo_toml));
+    cu::debug!("resolver: {resolver}");
+
+    let lockfile_path = workspace_root.join("Cargo.lock");
+    let lockfile_path = if lockfile_path.exists() {
+        cu::debug!("found Cargo.lock at {}", lockfile_path.display());
+        Some(lockfile_path)
+    } else {
+        cu::debug!("no Cargo.lock found at {}", lockfile_path.display());
+        None
+    };
+
     Ok(CargoManifestInfo {
         package_name,
         lib_entrypoint,
@@ -280,9 +422,147 @@ pub fn prepare(manifest_path: &Path) -> cu::Result<CargoManifestInfo> {
         resolved_target,
         dep_features,
         default_features,
+        lockfile_path,
+        resolver,
+        resolved_lints,
+        test_edition,
+        test_default_features: false,
+        resolved_patch,
     })
 }
 
+/// Get the [patch] and [replace] sections of a Cargo.toml, with the paths
+/// resolved relative to `base_path`
+fn resolve_patch_sections(cargo_toml: &toml::Table, base_path: &Path) -> cu::Result<toml::Table> {
+    let mut output = toml::Table::new();
+    if let Some(patch) = cargo_toml.get("patch") {
+        let mut patch = patch.clone();
+        if let Some(sources) = patch.as_table_mut() {
+            for (source, deps) in sources {
+                cu::debug!("resolving paths in [patch.{source}]");
+                resolve_dependency_paths_in_table(deps, base_path, None)?;
+            }
+        }
+        output.insert("patch".to_string(), patch);
+    }
+    if let Some(replace) = cargo_toml.get("replace") {
+        let mut replace = replace.clone();
+        cu::debug!("resolving paths in [replace]");
+        resolve_dependency_paths_in_table(&mut replace, base_path, None)?;
+        output.insert("replace".to_string(), replace);
+    }
+    Ok(output)
+}
+
+/// Get the target-specific dependencies of a Cargo.toml (`[target.<cfg>.dependencies]`).
+///
+/// They are kept, so when `--target` is passed to cargo, the test package resolves
+/// the same dependencies as the crate. dev-dependencies are not needed, same as the top level
+fn resolve_target_dependencies(cargo_toml: &toml::Table) -> Option<toml::Table> {
+    let target = cargo_toml.get("target")?.as_table()?;
+    let mut resolved_target = toml::Table::new();
+    for (cfg, value) in target {
+        let Some(value) = value.as_table() else {
+            cu::trace!("target '{cfg}' is not a table, skipping");
+            continue;
+        };
+        let mut resolved = toml::Table::new();
+        for key in ["dependencies", "build-dependencies"] {
+            if let Some(deps) = value.get(key) {
+                resolved.insert(key.to_string(), deps.clone());
+            }
+        }
+        if !resolved.is_empty() {
+            resolved_target.insert(cfg.clone(), toml::Value::Table(resolved));
+        }
+    }
+    Some(resolved_target)
+}
+
+/// Replace `<field>.workspace = true` in the [package] section with the value from
+/// [workspace.package], since the copied package is not in the same workspace
+fn resolve_inherited_package_fields(
+    cargo_toml: &mut toml::Table,
+    workspace: Option<&toml::Table>,
+    workspace_root: &Path,
+) -> cu::Result<()> {
+    let Some(package) = cargo_toml.get_mut("package").and_then(|p| p.as_table_mut()) else {
+        return Ok(());
+    };
+    let workspace_package = workspace
+        .and_then(|workspace| workspace.get("package"))
+        .and_then(|package| package.as_table());
+    for (key, value) in package.iter_mut() {
+        let is_inherited = value
+            .get("workspace")
+            .and_then(|x| x.as_bool())
+            .unwrap_or_default();
+        if !is_inherited {
+            continue;
+        }
+        cu::trace!("resolving inherited package.{key}");
+        let inherited = cu::check!(
+            workspace_package.and_then(|package| package.get(key)),
+            "package.{key} is inherited from workspace, but workspace.package.{key} is not found"
+        )?;
+        let mut inherited = inherited.clone();
+        // paths are relative to the workspace root
+        if matches!(key.as_str(), "readme" | "license-file")
+            && let Some(path) = inherited.as_str()
+        {
+            inherited = toml::Value::String(workspace_root.join(path).into_utf8()?);
+        }
+        *value = inherited;
+    }
+    Ok(())
+}
+
+/// Get the edition of the package, which might be inherited from the workspace
+fn detect_edition<'a>(
+    cargo_toml: &'a toml::Table,
+    workspace: Option<&'a toml::Table>,
+) -> Option<&'a str> {
+    match cargo_toml.get("package")?.get("edition")? {
+        toml::Value::String(edition) => Some(edition.as_str()),
+        _ => workspace?.get("package")?.get("edition")?.as_str(),
+    }
+}
+
+/// Detect the resolver used when building the package normally, from the Cargo.toml
+/// of the workspace root (or the package if not in a workspace).
+///
+/// Same as cargo, this is `workspace.resolver`, `package.resolver`, or the default resolver
+/// for the edition of the root package. A virtual workspace without `resolver` uses `"1"`
+fn detect_resolver(manifest: &toml::Table) -> String {
+    let workspace = manifest.get("workspace").and_then(|x| x.as_table());
+    let workspace_resolver = workspace
+        .and_then(|workspace| workspace.get("resolver"))
+        .and_then(|resolver| resolver.as_str());
+    if let Some(resolver) = workspace_resolver {
+        cu::trace!("found workspace.resolver");
+        return resolver.to_string();
+    }
+    let Some(package) = manifest.get("package") else {
+        cu::trace!("virtual workspace without resolver, using resolver 1");
+        return "1".to_string();
+    };
+    if let Some(resolver) = package
+        .get("resolver")
+        .and_then(|resolver| resolver.as_str())
+    {
+        cu::trace!("found package.resolver");
+        return resolver.to_string();
+    }
+    let edition = detect_edition(manifest, workspace);
+    cu::trace!("inferring resolver from edition: {edition:?}");
+    match edition {
+        Some("2024") => "3",
+        Some("2021") => "2",
+        _ => "1",
+    }
+    .to_string()
+}
+
 fn resolve_paths_in_workspace(
     workspace_table: &mut toml::Value,
     base_path: &Path,
@@ -481,6 +761,8 @@ pub fn make_test_package_manifest(
     cu::debug!("preparing test package manifest");
     let package_name = &manifest_info.package_name;
 
+    // `links` of the crate is not copied, since only one package in the build
+    // can link the same native library, which is `__layer_test`
     let mut test_package_manifest = toml! {
         [package]
         name = ""
@@ -492,8 +774,14 @@ pub fn make_test_package_manifest(
         default = []
     };
     test_package_manifest["package"]["name"] = toml::Value::String(test_package_name.to_string());
-
-    // add the dependencies from the main package to the test package
+    test_package_manifest["package"]["edition"] =
+        toml::Value::String(manifest_info.test_edition.clone());
+
+    // add the dependencies from the main package to the test package.
+    // these are needed even though `__layer_test` declares them, since the
+    // modules being tested are compiled as part of the test package, and they
+    // can use the external crates directly. This doesn't add compile time, since
+    // the same dependencies (with the same features) are built for `__layer_test` anyway
     if let Some(deps) = &manifest_info.resolved_dependencies {
         test_package_manifest.insert("dependencies".to_string(), toml::Value::Table(deps.clone()));
     }
@@ -506,6 +794,10 @@ pub fn make_test_package_manifest(
     if let Some(target) = &manifest_info.resolved_target {
         test_package_manifest.insert("target".to_string(), toml::Value::Table(target.clone()));
     }
+    // the layers should be checked with the same lints as the crate
+    if let Some(lints) = &manifest_info.resolved_lints {
+        test_package_manifest.insert("lints".to_string(), toml::Value::Table(lints.clone()));
+    }
     let test_package_deps = test_package_manifest
         .entry("dependencies")
         .or_insert(toml::Value::Table(toml::Table::new()));
@@ -519,6 +811,12 @@ pub fn make_test_package_manifest(
         }
     };
     main_package_dep["path"] = toml::Value::String(format!("../{package_name}"));
+    if manifest_info.test_default_features {
+        // the default features are still forwarded below, which is redundant
+        // but keeps the features of the test package the same as the crate
+        cu::debug!("enabling default features of the crate in test package");
+        main_package_dep["default-features"] = toml::Value::Boolean(true);
+    }
     test_package_deps.as_table_mut().unwrap().insert(
         "__layer_test".to_string(),
         toml::Value::Table(main_package_dep),
@@ -551,3 +849,131 @@ pub fn make_test_package_manifest(
 
     Ok(test_package_manifest)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_dependencies_are_forwarded() {
+        let cargo_toml: toml::Table = toml::parse(
+            r#"
+            [package]
+            name = "a"
+            [target.'cfg(windows)'.dependencies]
+            winapi = "0.3"
+            [target.'cfg(windows)'.dev-dependencies]
+            tempfile = "3"
+            [target.'cfg(unix)'.build-dependencies]
+            cc = "1"
+            [target.'cfg(unix)'.dev-dependencies]
+            tempfile = "3"
+            [target.wasm32-unknown-unknown]
+            runner = "wasm-bindgen-test-runner"
+            "#,
+        )
+        .unwrap();
+        let expected: toml::Table = toml::parse(
+            r#"
+            ['cfg(windows)'.dependencies]
+            winapi = "0.3"
+            ['cfg(unix)'.build-dependencies]
+            cc = "1"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(resolve_target_dependencies(&cargo_toml), Some(expected));
+    }
+
+    #[test]
+    fn test_no_target_dependencies() {
+        let cargo_toml: toml::Table = toml::parse("[package]\nname = \"a\"\n").unwrap();
+        assert_eq!(resolve_target_dependencies(&cargo_toml), None);
+    }
+
+    #[test]
+    fn test_patch_sections_paths_are_resolved() {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let cargo_toml: toml::Table = toml::parse(
+            r#"
+            [package]
+            name = "a"
+            [patch.crates-io]
+            foo = { path = "src" }
+            bar = "1"
+            [patch."https://github.com/example/baz"]
+            baz = { path = "./src/../src", branch = "main" }
+            [replace]
+            "qux:0.1.0" = { path = "src" }
+            "#,
+        )
+        .unwrap();
+        let resolved = resolve_patch_sections(&cargo_toml, base_path).unwrap();
+        let src = base_path
+            .join("src")
+            .normalize()
+            .unwrap()
+            .into_utf8()
+            .unwrap();
+        let mut expected: toml::Table = toml::parse(
+            r#"
+            [patch.crates-io]
+            foo = { path = "" }
+            bar = "1"
+            [patch."https://github.com/example/baz"]
+            baz = { path = "", branch = "main" }
+            [replace]
+            "qux:0.1.0" = { path = "" }
+            "#,
+        )
+        .unwrap();
+        expected["patch"]["crates-io"]["foo"]["path"] = src.clone().into();
+        expected["patch"]["https://github.com/example/baz"]["baz"]["path"] = src.clone().into();
+        expected["replace"]["qux:0.1.0"]["path"] = src.into();
+        assert_eq!(resolved, expected);
+    }
+
+    #[test]
+    fn test_links_is_not_declared_in_test_package() {
+        let dir = std::env::temp_dir().join(format!("layered-crate-links-{}", std::process::id()));
+        let manifest_path = dir.join("Cargo.toml");
+        cu::fs::write(
+            &manifest_path,
+            "[package]\nname = \"native\"\nedition = \"2021\"\nlinks = \"foo\"\n",
+        )
+        .unwrap();
+        cu::fs::write(dir.join("src").join("lib.rs"), "pub mod ffi {}\n").unwrap();
+        let manifest_info = prepare(&manifest_path, Some(&dir), None);
+        let _ = std::fs::remove_dir_all(&dir);
+        let manifest_info = manifest_info.unwrap();
+        // the crate itself still links the library
+        let content: toml::Table = toml::parse(&manifest_info.content).unwrap();
+        assert_eq!(content["package"]["links"].as_str(), Some("foo"));
+
+        let test_manifest =
+            make_test_package_manifest(&manifest_info, "native-layer-test-6").unwrap();
+        let test_manifest: toml::Table = toml::parse(&test_manifest).unwrap();
+        assert!(test_manifest["package"].get("links").is_none());
+        assert!(test_manifest["dependencies"].get("__layer_test").is_some());
+    }
+
+    #[test]
+    fn test_resolver_from_workspace_root() {
+        let resolver = |manifest: &str| detect_resolver(&toml::parse(manifest).unwrap());
+        assert_eq!(resolver("package.edition = \"2021\""), "2");
+        assert_eq!(
+            resolver("package = { edition = \"2021\", resolver = \"1\" }"),
+            "1"
+        );
+        assert_eq!(
+            resolver("package.edition.workspace = true\nworkspace.package.edition = \"2024\""),
+            "3"
+        );
+        // virtual workspace
+        assert_eq!(
+            resolver("workspace = { members = [\"a\"], resolver = \"2\" }"),
+            "2"
+        );
+        assert_eq!(resolver("workspace.members = [\"a\"]"), "1");
+    }
+}
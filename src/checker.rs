@@ -1,36 +1,265 @@
-use std::collections::BTreeSet;
-use std::path::Path;
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use cu::pre::*;
 use itertools::Itertools;
 
 use crate::layerfile::{DepGraph, LayerFile};
-use crate::syntax::EntryFile;
+use crate::syntax::{self, EntryFile};
+use crate::util;
 
+/// Result of checking a crate by layers
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Result of each layer, in the order they are checked.
+    ///
+    /// Layers after a failed layer are not checked, and are not in the report
+    pub layers: Vec<LayerReport>,
+}
+
+impl Report {
+    /// If all checked layers passed (possibly with warnings)
+    pub fn is_success(&self) -> bool {
+        self.layers
+            .iter()
+            .all(|layer| layer.status != LayerStatus::Fail)
+    }
+
+    /// Get the names of the layers that failed
+    pub fn failed_layers(&self) -> impl Iterator<Item = &str> {
+        self.layers
+            .iter()
+            .filter(|layer| layer.status == LayerStatus::Fail)
+            .map(|layer| layer.name.as_str())
+    }
+
+    /// Get the names of the layers that passed with warnings
+    pub fn warned_layers(&self) -> impl Iterator<Item = &str> {
+        self.layers
+            .iter()
+            .filter(|layer| layer.status == LayerStatus::Warn)
+            .map(|layer| layer.name.as_str())
+    }
+}
+
+/// Result of checking one layer
+#[derive(Debug, Clone)]
+pub struct LayerReport {
+    /// Name of the layer
+    pub name: String,
+    pub status: LayerStatus,
+    /// Number of modules built in the test library of the layer
+    /// (the layer and the layers it implements from)
+    pub modules: usize,
+    /// Features the layer is checked with, if `crate.feature-matrix` is set
+    pub features: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayerStatus {
+    /// The layer built successfully
+    Pass,
+    /// The layer built successfully, but with warnings
+    Warn,
+    /// The layer failed to build
+    Fail,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn build_by_layers(
-    args: &crate::Cli,
+    args: &crate::Options,
+    rust_flags: Option<&str>,
     manifest_path: &Path,
+    temp_dir: &Path,
     package_dir: &Path,
-    test_package_dir: &Path,
+    test_package_name: &str,
     layerfile: &LayerFile,
     dep_graph: &DepGraph,
     entryfile: &EntryFile,
-) -> cu::Result<()> {
+    only_layers: Option<&BTreeSet<String>>,
+) -> cu::Result<Report> {
     let manifest_path = manifest_path.normalize()?;
     let manifest_dir = manifest_path.parent_abs()?;
+    let target_dir = if args.read_only {
+        Some(temp_dir.normalize()?.join("target"))
+    } else {
+        None
+    };
+    let hints = layerfile
+        .crate_
+        .hints
+        .iter()
+        .map(|(pattern, hint)| {
+            let regex = cu::check!(
+                regex::Regex::new(pattern),
+                "invalid pattern `{pattern}` in crate.hints"
+            )?;
+            Ok((regex, hint.clone()))
+        })
+        .collect::<cu::Result<Vec<_>>>()?;
+    let runner = CargoRunner {
+        args: &args.cargo_args,
+        rust_flags,
+        manifest_path: &manifest_path,
+        manifest_dir: &manifest_dir,
+        verbose: args.verbose_cargo,
+        json_logs: args.json_logs,
+        explain: args.explain_error.then_some(layerfile),
+        driver: (!layerfile.crate_.driver.is_empty()).then_some(&layerfile.crate_.driver[..]),
+        module_paths: &entryfile.top_module_to_paths,
+        target_dir: target_dir.as_deref(),
+        hints: &hints,
+    };
+    let extra_modules = find_extra_modules(layerfile, dep_graph, entryfile);
+    cu::debug!("extra modules: {:?}", extra_modules);
+    if !extra_modules.is_empty() {
+        cu::info!(
+            "modules not in any layer, which are not checked: {}",
+            extra_modules.iter().join(", ")
+        );
+    }
+
     // first run cargo once on the initial state
-    let all_deps_str = dep_graph.top_down_order.join(",");
-    run_cargo(
-        None,
-        &args.cargo_args,
-        package_dir,
-        &manifest_path,
-        &manifest_dir,
-        &all_deps_str,
-    )?;
+    if args.no_baseline {
+        cu::debug!("skipping building the full crate");
+    } else {
+        let all_deps_str = dep_graph.top_down_order.join(",");
+        match &args.baseline_args {
+            Some(baseline_args) => {
+                let mut baseline_args = baseline_args
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect::<Vec<_>>();
+                util::add_color_flag_if_missing(&mut baseline_args);
+                let runner = CargoRunner {
+                    args: &baseline_args,
+                    ..runner
+                };
+                runner.run(CargoTarget::Crate, package_dir, &all_deps_str)?;
+            }
+            None => {
+                runner.run(CargoTarget::Crate, package_dir, &all_deps_str)?;
+            }
+        }
+    }
+
+    let mut plans = Vec::with_capacity(dep_graph.top_down_order.len());
+    for layer in &dep_graph.top_down_order {
+        if only_layers.is_some_and(|only| !only.contains(layer)) {
+            cu::debug!("skipping layer '{layer}'");
+            continue;
+        }
+        plans.push(plan_layer(layer, layerfile, dep_graph, entryfile)?);
+    }
+
+    if args.diff {
+        cu::check!(
+            diff_test_libs(temp_dir, &plans),
+            "failed to compare test libraries with the previous run"
+        )?;
+    }
+
+    let has_layer_rust_flags = plans
+        .iter()
+        .any(|plan| !layerfile.layer[&plan.layer].rustflags.is_empty());
+    let feature_matrix = &layerfile.crate_.feature_matrix;
+    if args.batch && !feature_matrix.is_empty() {
+        cu::warn!(
+            "--batch is ignored when crate.feature-matrix is set, layers will be checked one by one"
+        );
+    } else if args.batch && runner.driver.is_some() {
+        cu::warn!("--batch is ignored when crate.driver is set, layers will be checked one by one");
+    } else if args.batch && has_layer_rust_flags {
+        cu::warn!(
+            "--batch is ignored when layers have rustflags, layers will be checked one by one"
+        );
+    } else if args.batch && plans.len() > 1 {
+        let report = cu::check!(
+            build_batch(&runner, temp_dir, test_package_name, &plans),
+            "failed to build layers in batch"
+        )?;
+        if let Some(report) = report {
+            return Ok(report);
+        }
+    }
+
+    let test_package_dir = temp_dir.join(test_package_name);
+    let test_package_entrypoint = test_package_dir.join("lib.rs");
+
+    // check with the default features if there is no feature matrix
+    let feature_sets = if feature_matrix.is_empty() {
+        vec![None]
+    } else if runner.driver.is_some() {
+        cu::warn!(
+            "crate.feature-matrix is ignored when crate.driver is set, since the features cannot be passed to the driver"
+        );
+        vec![None]
+    } else {
+        feature_matrix.iter().map(Some).collect()
+    };
+
+    let mut report = Report::default();
+    // now we check each layer
+    'outer: for plan in &plans {
+        let layer = plan.layer.as_str();
+        cu::check!(
+            cu::fs::write(&test_package_entrypoint, &plan.test_file),
+            "failed to write test library to file"
+        )?;
+        let layer_rust_flags = &layerfile.layer[layer].rustflags;
+        let rust_flags = if layer_rust_flags.is_empty() {
+            None
+        } else {
+            let rust_flags = util::extend_rustflags(runner.rust_flags, layer_rust_flags);
+            cu::debug!("RUSTFLAGS for layer '{layer}': {rust_flags}");
+            Some(rust_flags)
+        };
+        for features in &feature_sets {
+            let cargo_args = match features {
+                None => runner.args.to_vec(),
+                Some(features) => {
+                    cu::info!(
+                        "checking layer '{layer}' with features: [{}]",
+                        features.join(", ")
+                    );
+                    let feature_args = vec![
+                        "--no-default-features".to_string(),
+                        "--features".to_string(),
+                        features.join(","),
+                    ];
+                    util::insert_cargo_args(runner.args, feature_args)
+                }
+            };
+            let runner = CargoRunner {
+                args: &cargo_args,
+                rust_flags: rust_flags.as_deref().or(runner.rust_flags),
+                ..runner
+            };
+            let status =
+                runner.run(CargoTarget::Layer(layer), &test_package_dir, &plan.deps_str)?;
+            report.layers.push(LayerReport {
+                name: layer.to_string(),
+                status,
+                modules: plan.modules,
+                features: features.cloned(),
+            });
+            if status == LayerStatus::Fail {
+                break 'outer;
+            }
+        }
+    }
+
+    Ok(report)
+}
 
-    // find extra modules that will always be included
+/// Find the modules that are not in any layer, and not excluded
+pub fn find_extra_modules(
+    layerfile: &LayerFile,
+    dep_graph: &DepGraph,
+    entryfile: &EntryFile,
+) -> BTreeSet<String> {
     let mut extra_modules = entryfile.all_modules();
     cu::debug!("all modules: {:?}", extra_modules);
     // if a module is in the dep graph, then it's not "extra"
@@ -41,134 +270,652 @@ pub fn build_by_layers(
     for module in &layerfile.crate_.exclude {
         extra_modules.remove(module);
     }
-    cu::debug!("extra modules: {:?}", extra_modules);
+    extra_modules
+}
 
-    let test_package_entrypoint = test_package_dir.join("lib.rs");
+/// List the modules that are not checked as layers, with the reason.
+///
+/// These are the extra modules (not in any layer) and the excluded modules
+pub fn list_extra_modules(
+    layerfile: &LayerFile,
+    dep_graph: &DepGraph,
+    entryfile: &EntryFile,
+) -> Vec<(String, String)> {
+    let mut output = find_extra_modules(layerfile, dep_graph, entryfile)
+        .into_iter()
+        .map(|m| (m, "not in any layer".to_string()))
+        .collect::<Vec<_>>();
+    for module in &layerfile.crate_.exclude {
+        let unexcluded_by = layerfile
+            .layer
+            .iter()
+            .filter(|(_, layer)| layer.unexclude.contains(module))
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        let reason = if unexcluded_by.is_empty() {
+            "in crate.exclude, not available to any layer".to_string()
+        } else {
+            format!(
+                "in crate.exclude, only available to layer(s) with unexclude: {}",
+                unexcluded_by.join(", ")
+            )
+        };
+        output.push((module.clone(), reason));
+    }
+    output
+}
 
-    // now we check each layer
-    for layer in &dep_graph.top_down_order {
-        let all_test_modules = cu::check!(
-            layerfile.get_test_modules(layer),
-            "failed to get test modules for layer '{layer}'"
-        )?;
+/// What to build for a layer
+pub struct LayerPlan {
+    pub layer: String,
+    /// Content of the generated test library
+    pub test_file: String,
+    /// Dependencies of the layer, joined by comma
+    pub deps_str: String,
+    /// Number of modules in the test library
+    pub modules: usize,
+}
 
-        let mut all_deps = BTreeSet::new();
-        // collect all dependencies of the layer
-        for m in &all_test_modules {
-            if let Some(deps) = dep_graph.deps.get(m) {
-                all_deps.extend(deps.iter().cloned());
+/// Print the difference of the test library of each layer from the previous run,
+/// and keep the new ones in the temp dir for the next run
+fn diff_test_libs(temp_dir: &Path, plans: &[LayerPlan]) -> cu::Result<()> {
+    let previous_dir = temp_dir.join("previous");
+    for plan in plans {
+        let layer = &plan.layer;
+        let path = previous_dir.join(format!("{layer}.rs"));
+        if !path.exists() {
+            cu::info!("no test library of layer '{layer}' from the previous run");
+        } else {
+            let previous = cu::fs::read_string(&path)?;
+            let diff = util::unified_diff(&previous, &plan.test_file);
+            if diff.is_empty() {
+                cu::info!("test library of layer '{layer}' is unchanged");
+            } else {
+                cu::info!("test library of layer '{layer}' changed:\n{diff}");
             }
         }
-        // deduplicate the deps from ones already in test module
-        for m in &all_test_modules {
-            all_deps.remove(m);
+        cu::fs::write(&path, &plan.test_file)?;
+    }
+    Ok(())
+}
+
+pub fn plan_layer(
+    layer: &str,
+    layerfile: &LayerFile,
+    dep_graph: &DepGraph,
+    entryfile: &EntryFile,
+) -> cu::Result<LayerPlan> {
+    let all_test_modules = cu::check!(
+        layerfile.get_test_modules(layer),
+        "failed to get test modules for layer '{layer}'"
+    )?;
+
+    let mut all_deps = BTreeSet::new();
+    // collect all dependencies of the layer
+    for m in &all_test_modules {
+        if let Some(deps) = dep_graph.deps.get(m) {
+            all_deps.extend(deps.iter().cloned());
+        }
+    }
+    // deduplicate the deps from ones already in test module
+    let mut merged = Vec::new();
+    for m in &all_test_modules {
+        if all_deps.remove(m) {
+            merged.push(m.as_str());
         }
+    }
+    cu::debug!(
+        "layer '{layer}': test modules: {all_test_modules:?}, dependencies: {all_deps:?}, dependencies checked as test modules: {merged:?}"
+    );
 
-        // build with all dependencies of the layer
-        let test_file = cu::check!(
-            entryfile.produce_test_lib(&all_test_modules, &all_deps),
-            "failed to produce test library for module '{layer}'"
-        )?;
+    // modules included (or unexcluded) by the layer can be used, but don't have to be
+    let mut included_modules = BTreeSet::new();
+    let layer_config = &layerfile.layer[layer];
+    for m in layer_config
+        .also_include
+        .iter()
+        .chain(&layer_config.unexclude)
+    {
+        if !all_test_modules.contains(m) && !all_deps.contains(m) {
+            included_modules.insert(m.clone());
+        }
+    }
+    // public layers can be used by any layer, except the ones they depend on
+    for (name, config) in &layerfile.layer {
+        if !config.public || all_test_modules.contains(name) || all_deps.contains(name) {
+            continue;
+        }
+        if all_test_modules
+            .iter()
+            .any(|m| dep_graph.dependents_of(m).contains(name))
+        {
+            cu::trace!("public layer `{name}` depends on layer '{layer}', not including it");
+            continue;
+        }
+        included_modules.insert(name.clone());
+    }
+
+    let mut glob_imports = BTreeSet::new();
+    for m in &layerfile.layer[layer].glob_import {
+        if all_deps.contains(m) {
+            glob_imports.insert(m.clone());
+        } else {
+            cu::warn!("`{m}` in glob-import of layer '{layer}' is not a dependency, ignoring");
+        }
+    }
+
+    let strip_tests = all_test_modules
+        .iter()
+        .filter(|m| layerfile.layer.get(*m).is_some_and(|l| l.exclude_tests))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    // build with all dependencies of the layer
+    let test_file = cu::check!(
+        entryfile.produce_test_lib(
+            &all_test_modules,
+            &all_deps,
+            &included_modules,
+            &layerfile.layer[layer],
+            &glob_imports,
+            &strip_tests
+        ),
+        "failed to produce test library for module '{layer}'"
+    )?;
+    Ok(LayerPlan {
+        layer: layer.to_string(),
+        test_file,
+        deps_str: all_deps.iter().join(","),
+        modules: all_test_modules.len(),
+    })
+}
+
+/// Build all layers with one cargo invocation, each layer in its own test package.
+///
+/// Returns `None` if the batch failed to build or has warnings, in which case the layers
+/// need to be built one by one to find which layer the errors or warnings are from,
+/// since the diagnostics of the batch cannot be attributed to a layer
+fn build_batch(
+    runner: &CargoRunner,
+    temp_dir: &Path,
+    test_package_name: &str,
+    plans: &[LayerPlan],
+) -> cu::Result<Option<Report>> {
+    cu::debug!("building {} layers in batch", plans.len());
+    let mut layers = Vec::with_capacity(plans.len());
+    let mut package_args = Vec::with_capacity(plans.len() * 2);
+    for plan in plans {
+        let package_name = util::batch_test_package_name(test_package_name, &plan.layer);
+        let entrypoint = temp_dir.join(&package_name).join("lib.rs");
         cu::check!(
-            cu::fs::write(&test_package_entrypoint, test_file),
-            "failed to write test library to file"
-        )?;
-        let deps_str = all_deps.iter().join(",");
-        run_cargo(
-            Some(layer),
-            &args.cargo_args,
-            test_package_dir,
-            &manifest_path,
-            &manifest_dir,
-            &deps_str,
+            cu::fs::write(&entrypoint, &plan.test_file),
+            "failed to write test library for layer '{}' to file",
+            plan.layer
         )?;
+        layers.push(plan.layer.clone());
+        package_args.push("-p".to_string());
+        package_args.push(package_name);
+    }
+    let args = util::insert_cargo_args(runner.args, package_args);
+    let runner = CargoRunner {
+        args: &args,
+        ..*runner
+    };
+    let all_deps_str = layers.join(",");
+    match runner.run(CargoTarget::Batch(&layers), temp_dir, &all_deps_str)? {
+        LayerStatus::Pass => {}
+        LayerStatus::Warn => {
+            cu::warn!(
+                "batch build has warning(s), building layers one by one to find the layer(s) with warning(s)"
+            );
+            return Ok(None);
+        }
+        LayerStatus::Fail => {
+            cu::warn!("batch build failed, building layers one by one to find the failed layer(s)");
+            return Ok(None);
+        }
     }
+    let layers = plans
+        .iter()
+        .map(|plan| LayerReport {
+            name: plan.layer.clone(),
+            features: None,
+            status: LayerStatus::Pass,
+            modules: plan.modules,
+        })
+        .collect();
+    Ok(Some(Report { layers }))
+}
 
-    Ok(())
+/// What to run cargo on
+#[derive(Clone, Copy)]
+enum CargoTarget<'a> {
+    /// The full crate
+    Crate,
+    /// One layer
+    Layer(&'a str),
+    /// Multiple layers in one invocation
+    Batch(&'a [String]),
 }
 
-fn run_cargo(
-    layer: Option<&str>,
-    args: &[String],
-    curdir: &Path,
-    manifest_path: &Path,
-    manifest_dir: &Path,
-    deps_layers_str: &str,
-) -> cu::Result<()> {
-    let has_warning = Arc::new(cu::Atomic::<bool, bool>::new_bool(false));
-    let command = cu::which("cargo")?
-        .command()
-        .args(args)
-        .current_dir(curdir)
-        .env("LAYERED_CRATE_ORIGINAL_MANIFEST_PATH", manifest_path)
-        .env("LAYERED_CRATE_ORIGINAL_MANIFEST_DIR", manifest_dir)
-        .env("LAYERED_CRATE_DEPS_LAYERS", deps_layers_str)
-        .env("LAYERED_CRATE_TESTING_LAYER", layer.unwrap_or_default());
-    let print_diag = {
-        let has_warning = Arc::clone(&has_warning);
-        move |is_warning: bool, message: &str| {
-            has_warning.set(true);
-            if is_warning {
-                cu::warn!("{message}");
-                return;
+#[derive(Clone, Copy)]
+struct CargoRunner<'a> {
+    args: &'a [String],
+    rust_flags: Option<&'a str>,
+    manifest_path: &'a Path,
+    manifest_dir: &'a Path,
+    /// Show the full output of cargo instead of only the diagnostics
+    verbose: bool,
+    /// Command to run instead of cargo when checking layers
+    driver: Option<&'a [String]>,
+    /// Print events as JSON to stdout
+    json_logs: bool,
+    /// Paths of the top-level modules, for finding the module of a diagnostic
+    module_paths: &'a BTreeMap<String, String>,
+    /// Suggest changes to the Layerfile when a layer fails, with `--explain-error`
+    explain: Option<&'a LayerFile>,
+    /// Target dir to override the configured one with, with `--read-only`
+    target_dir: Option<&'a Path>,
+    /// Hints from `crate.hints`, shown when an error matches the pattern
+    hints: &'a [(regex::Regex, String)],
+}
+
+/// Event printed with `--json-logs`, one JSON object per line.
+///
+/// `layer` is `None` when building the full crate, and the layers
+/// separated by `,` when building in batch
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Event<'a> {
+    /// Started building the layer
+    Start { layer: Option<&'a str> },
+    /// Finished building the layer
+    Result {
+        layer: Option<&'a str>,
+        status: LayerStatus,
+    },
+    /// Diagnostic message from cargo
+    Diagnostic {
+        layer: Option<&'a str>,
+        is_warning: bool,
+        message: &'a str,
+        location: Option<&'a DiagnosticLocation>,
+    },
+}
+
+/// Location of the primary span of a diagnostic
+#[derive(Serialize)]
+struct DiagnosticLocation {
+    file: PathBuf,
+    line: usize,
+    column: usize,
+    /// The top-level module the file is in, if any
+    module: Option<String>,
+}
+
+impl DiagnosticLocation {
+    /// Parse the location from the `-->` line of the rendered diagnostic.
+    ///
+    /// The cargo preset only exposes the rendered message, so the structured
+    /// spans are not available
+    fn parse(
+        message: &str,
+        curdir: &Path,
+        module_paths: &BTreeMap<String, String>,
+    ) -> Option<Self> {
+        let message = strip_ansi(message);
+        let location = message
+            .lines()
+            .find_map(|line| line.trim_start().strip_prefix("--> "))?;
+        let mut parts = location.trim().rsplitn(3, ':');
+        let column = parts.next()?.parse().ok()?;
+        let line = parts.next()?.parse().ok()?;
+        let file = curdir.join(parts.next()?);
+        let module = syntax::find_module(&file, module_paths).map(str::to_string);
+        Some(Self {
+            file,
+            line,
+            column,
+            module,
+        })
+    }
+}
+
+fn strip_ansi(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip to the end of the escape sequence
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
             }
-            cu::error!("{message}");
-            print_guessed_hint_for_error(message);
+            continue;
         }
-    };
-    let name = match layer {
-        Some(layer) => format!("building layer '{layer}'"),
-        None => "build full crate".to_string(),
-    };
-    let done_message = match layer {
-        Some(layer) => format!("PASS {layer}"),
-        None => format!("{name}: OK"),
-    };
-    let command = command.preset(
-        cu::pio::cargo(name)
-            .on_diagnostic(print_diag)
-            .configure_spinner(|builder| builder.when_done(done_message)),
-    );
-    let (child, bar) = command.spawn()?;
-    match child.wait_nz() {
-        Ok(()) => {
-            match layer {
-                Some(layer) => {
-                    cu::progress!(bar, "PASS {layer}");
-                    bar.done();
-                    if has_warning.get() {
-                        cu::warn!("layer '{layer}' passed with warning(s).");
+        out.push(c);
+    }
+    out
+}
+
+impl Event<'_> {
+    fn emit(&self) {
+        match json::stringify(self) {
+            Ok(x) => println!("{x}"),
+            Err(e) => cu::debug!("failed to serialize event: {e:?}"),
+        }
+    }
+}
+
+impl CargoRunner<'_> {
+    /// Run cargo (or the driver, for layers) on the target.
+    ///
+    /// Failure to build the full crate is an error, while failure to build
+    /// layers is returned as [`LayerStatus::Fail`]
+    fn run(
+        &self,
+        target: CargoTarget<'_>,
+        curdir: &Path,
+        deps_layers_str: &str,
+    ) -> cu::Result<LayerStatus> {
+        let testing_layer = match target {
+            CargoTarget::Crate => String::new(),
+            CargoTarget::Layer(layer) => layer.to_string(),
+            CargoTarget::Batch(layers) => layers.join(","),
+        };
+        if !self.json_logs {
+            return self.run_internal(target, curdir, deps_layers_str, testing_layer);
+        }
+        let layer = (!testing_layer.is_empty()).then(|| testing_layer.clone());
+        Event::Start {
+            layer: layer.as_deref(),
+        }
+        .emit();
+        let result = self.run_internal(target, curdir, deps_layers_str, testing_layer);
+        let status = match &result {
+            Ok(status) => *status,
+            Err(_) => LayerStatus::Fail,
+        };
+        Event::Result {
+            layer: layer.as_deref(),
+            status,
+        }
+        .emit();
+        result
+    }
+
+    fn run_internal(
+        &self,
+        target: CargoTarget<'_>,
+        curdir: &Path,
+        deps_layers_str: &str,
+        testing_layer: String,
+    ) -> cu::Result<LayerStatus> {
+        let has_warning = Arc::new(cu::Atomic::<bool, bool>::new_bool(false));
+        // errors, and the modules they are in, to explain when the layer fails
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let event_layer =
+            (self.json_logs && !testing_layer.is_empty()).then(|| testing_layer.clone());
+        let json_logs = self.json_logs;
+        // the full crate is always built with cargo
+        let driver = match target {
+            CargoTarget::Crate => None,
+            _ => self.driver,
+        };
+        let command = match driver {
+            Some(driver) => {
+                let Some((program, driver_args)) = driver.split_first() else {
+                    cu::bail!("crate.driver is empty");
+                };
+                let program = cu::check!(cu::which(program), "cannot find driver `{program}`")?;
+                program.command().args(driver_args)
+            }
+            None => cu::which("cargo")?.command().args(self.args),
+        };
+        let mut command = command
+            .current_dir(curdir)
+            .env("LAYERED_CRATE_ORIGINAL_MANIFEST_PATH", self.manifest_path)
+            .env("LAYERED_CRATE_ORIGINAL_MANIFEST_DIR", self.manifest_dir)
+            .env("LAYERED_CRATE_DEPS_LAYERS", deps_layers_str)
+            .env("LAYERED_CRATE_TESTING_LAYER", testing_layer)
+            .env(crate::ACTIVE_ENV, "1");
+        if let Some(rust_flags) = self.rust_flags {
+            command = command.env("RUSTFLAGS", rust_flags);
+        }
+        // keep incremental artifacts in the target dir of the temp dir between layers
+        // and runs, which is not the default for all profiles
+        if std::env::var_os("CARGO_INCREMENTAL").is_none() {
+            command = command.env("CARGO_INCREMENTAL", "1");
+        }
+        if let Some(target_dir) = self.target_dir {
+            command = command.env("CARGO_TARGET_DIR", target_dir);
+        }
+        let name = match target {
+            CargoTarget::Crate => "build full crate".to_string(),
+            CargoTarget::Layer(layer) => format!("building layer '{layer}'"),
+            CargoTarget::Batch(layers) => format!("building {} layers in batch", layers.len()),
+        };
+        let (child, bar) = if self.verbose || driver.is_some() {
+            // warnings cannot be detected since the output is not parsed
+            cu::info!("{name}");
+            let child = command.all_inherit().spawn()?;
+            (child, None)
+        } else {
+            let print_diag = {
+                let has_warning = Arc::clone(&has_warning);
+                let errors = Arc::clone(&errors);
+                let curdir = curdir.to_path_buf();
+                let module_paths = self.module_paths.clone();
+                let hints = self.hints.to_vec();
+                move |is_warning: bool, message: &str| {
+                    has_warning.set(true);
+                    let location = DiagnosticLocation::parse(message, &curdir, &module_paths);
+                    if json_logs {
+                        Event::Diagnostic {
+                            layer: event_layer.as_deref(),
+                            is_warning,
+                            message,
+                            location: location.as_ref(),
+                        }
+                        .emit();
+                    }
+                    if is_warning {
+                        cu::warn!("{message}");
+                        return;
+                    }
+                    cu::error!("{message}");
+                    let module = location.and_then(|x| x.module);
+                    if let Some(module) = &module {
+                        cu::hint!("(the error is in module `{module}`)");
+                    }
+                    print_guessed_hint_for_error(message, &hints);
+                    if let Ok(mut errors) = errors.lock() {
+                        errors.push((strip_ansi(message), module));
                     }
                 }
-                None => {
-                    if has_warning.get() {
-                        cu::warn!("initial build finished with warning(s).");
+            };
+            let done_message = match target {
+                CargoTarget::Crate => format!("{name}: OK"),
+                CargoTarget::Layer(layer) => format!("PASS {layer}"),
+                CargoTarget::Batch(layers) => format!("PASS {}", layers.join(", ")),
+            };
+            let command = command.preset(
+                cu::pio::cargo(name)
+                    .on_diagnostic(print_diag)
+                    .configure_spinner(|builder| builder.when_done(done_message)),
+            );
+            let (child, bar) = command.spawn()?;
+            (child, Some(bar))
+        };
+        match child.wait_nz() {
+            Ok(()) => {
+                match target {
+                    CargoTarget::Crate => {
+                        if bar.is_none() {
+                            cu::info!("build full crate: OK");
+                        }
+                        if has_warning.get() {
+                            cu::warn!("initial build finished with warning(s).");
+                        }
+                    }
+                    CargoTarget::Layer(layer) => {
+                        match bar {
+                            Some(bar) => {
+                                cu::progress!(bar, "PASS {layer}");
+                                bar.done();
+                            }
+                            None => cu::info!("PASS {layer}"),
+                        }
+                        if has_warning.get() {
+                            cu::warn!("layer '{layer}' passed with warning(s).");
+                        }
                     }
+                    CargoTarget::Batch(layers) => match bar {
+                        Some(bar) => bar.done(),
+                        None => cu::info!("PASS {}", layers.join(", ")),
+                    },
+                }
+                if has_warning.get() {
+                    Ok(LayerStatus::Warn)
+                } else {
+                    Ok(LayerStatus::Pass)
                 }
             }
-            Ok(())
-        }
-        Err(e) => {
-            drop(bar);
-            if let Some(layer) = layer {
-                cu::error!("FAIL {layer}");
-                cu::rethrow!(
-                    e,
-                    "layer '{layer}' failed to build (see cargo output above)"
-                );
+            Err(e) => {
+                drop(bar);
+                match target {
+                    CargoTarget::Crate => {
+                        cu::rethrow!(e, "crate failed to build (see cargo output above)");
+                    }
+                    CargoTarget::Layer(layer) => {
+                        cu::error!("FAIL {layer}");
+                        cu::error!("layer '{layer}' failed to build (see cargo output above)");
+                        if let Some(layerfile) = self.explain
+                            && let Ok(errors) = errors.lock()
+                        {
+                            explain_errors(layer, &errors, layerfile);
+                        }
+                    }
+                    CargoTarget::Batch(_) => {}
+                }
+                cu::debug!("cargo error: {e:?}");
+                Ok(LayerStatus::Fail)
             }
-            cu::rethrow!(e, "crate failed to build (see cargo output above)");
         }
     }
 }
 
+/// Print a table of the status of every layer in the order, after all the build output.
+///
+/// Layers not in the report (not checked) are shown as skipped
+pub fn print_summary(report: &Report, order: &[String]) {
+    let label = |layer: &LayerReport| match &layer.features {
+        None => layer.name.clone(),
+        Some(features) => format!("{} [{}]", layer.name, features.join(",")),
+    };
+    let width = order
+        .iter()
+        .map(|x| x.len())
+        .chain(report.layers.iter().map(|x| label(x).len()))
+        .chain(["LAYER".len()])
+        .max()
+        .unwrap_or_default();
+    let (green, yellow, red, gray, reset) = if cu::lv::color_enabled() {
+        ("\x1b[92m", "\x1b[93m", "\x1b[91m", "\x1b[90m", "\x1b[0m")
+    } else {
+        ("", "", "", "", "")
+    };
+    let mut lines = Vec::with_capacity(order.len() + 1);
+    lines.push(format!("{:<width$}  STATUS  MODULES", "LAYER"));
+    for layer in order {
+        // a layer has multiple reports if checked with a feature matrix
+        let mut layer_reports = report.layers.iter().filter(|x| &x.name == layer).peekable();
+        if layer_reports.peek().is_none() {
+            lines.push(format!("{layer:<width$}  {gray}SKIP{reset}    -"));
+            continue;
+        }
+        for layer_report in layer_reports {
+            let (color, status) = match layer_report.status {
+                LayerStatus::Pass => (green, "PASS"),
+                LayerStatus::Warn => (yellow, "WARN"),
+                LayerStatus::Fail => (red, "FAIL"),
+            };
+            lines.push(format!(
+                "{:<width$}  {color}{status}{reset}    {}",
+                label(layer_report),
+                layer_report.modules
+            ));
+        }
+    }
+    cu::info!("summary:\n{}", lines.join("\n"));
+}
+
 /// print a best-guess hint (if any) for an error line that matches
-fn print_guessed_hint_for_error(error: &str) {
+fn print_guessed_hint_for_error(error: &str, hints: &[(regex::Regex, String)]) {
     if error.contains("unused import") {
         cu::hint!("(you might have specified an extraneous dependency on this layer)");
+    } else if error.contains("unresolved import") {
+        cu::hint!("(you might be missing a dependency on this layer)");
+    }
+    if hints.is_empty() {
         return;
     }
-    if error.contains("unresolved import") {
-        cu::hint!("(you might be missing a dependency on this layer)");
+    let error = strip_ansi(error);
+    for (regex, hint) in hints {
+        if regex.is_match(&error) {
+            cu::hint!("{hint}");
+        }
+    }
+}
+
+/// Suggest changes to `depends-on` of the failed layer from the errors
+/// (the message without ANSI codes, and the module the error is in)
+fn explain_errors(layer: &str, errors: &[(String, Option<String>)], layerfile: &LayerFile) {
+    let Some(depends_on) = layerfile.layer.get(layer).map(|x| &x.depends_on) else {
+        return;
+    };
+    let mut to_add = BTreeSet::new();
+    let mut to_remove = BTreeSet::new();
+    for (message, module) in errors {
+        let Some(first_line) = message.lines().next() else {
+            continue;
+        };
+        let names = first_line.split('`').skip(1).step_by(2);
+        if first_line.contains("unresolved import") {
+            for name in names {
+                // the first segment after `crate::` is the module
+                let mut segments = name.split("::").filter(|x| *x != "crate");
+                if let Some(dep) = segments.next()
+                    && dep != layer
+                    && layerfile.layer.contains_key(dep)
+                    && !depends_on.iter().any(|x| x == dep)
+                {
+                    to_add.insert(dep.to_string());
+                }
+            }
+        } else if first_line.contains("unused import") && module.is_none() {
+            // unused imports not in any module are the dependencies
+            // imported in the generated test library (i.e. `::__layer_test::foo`)
+            for name in names {
+                let dep = name.rsplit("::").next().unwrap_or(name);
+                if depends_on.iter().any(|x| x == dep) {
+                    to_remove.insert(dep.to_string());
+                }
+            }
+        }
     }
+    if to_add.is_empty() && to_remove.is_empty() {
+        cu::debug!("no suggested changes for layer '{layer}'");
+        return;
+    }
+    let mut new_depends_on = depends_on
+        .iter()
+        .filter(|x| !to_remove.contains(*x))
+        .cloned()
+        .collect::<Vec<_>>();
+    new_depends_on.extend(to_add);
+    cu::hint!(
+        "suggested change to the Layerfile:\n [layer.{layer}]\n-depends-on = {depends_on:?}\n+depends-on = {new_depends_on:?}"
+    );
 }
@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use cu::pre::*;
+
+use crate::cargo_toml::{self, CargoManifestInfo};
+use crate::checker;
+use crate::layerfile::{DepGraph, LayerFile};
+use crate::syntax::EntryFile;
+use crate::util;
+
+/// What would be written and run for checking the crate, printed with `--describe-run`.
+///
+/// Paths are relative to the temp dir, so the output is the same on every machine
+#[derive(Serialize)]
+pub struct RunDescription {
+    /// Files of the workspace shared by all layers
+    pub files: Vec<FileDescription>,
+    pub layers: Vec<LayerDescription>,
+}
+
+#[derive(Serialize)]
+pub struct FileDescription {
+    pub path: String,
+    /// FNV-1a hash of the content, in hex
+    pub hash: String,
+}
+
+impl FileDescription {
+    fn new(path: impl AsRef<Path>, content: &str) -> Self {
+        Self {
+            path: util::to_forward_slashes(&path.as_ref().to_string_lossy()),
+            hash: format!("{:016x}", util::fnv1a(content.as_bytes())),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LayerDescription {
+    pub layer: String,
+    /// Files written before checking the layer
+    pub files: Vec<FileDescription>,
+    /// Program and args to run
+    pub command: Vec<String>,
+    /// Directory to run the command in
+    pub current_dir: String,
+    /// Environment variables set for the command
+    pub env: BTreeMap<&'static str, String>,
+}
+
+/// Describe the generated workspace and the command for each layer,
+/// without writing or running anything
+pub fn describe_run(
+    args: &crate::Options,
+    rust_flags: Option<&str>,
+    manifest_info: &CargoManifestInfo,
+    layerfile: &LayerFile,
+    dep_graph: &DepGraph,
+    entryfile: &EntryFile,
+) -> cu::Result<RunDescription> {
+    let package_name = &manifest_info.package_name;
+    let test_package_name = util::test_package_name(package_name);
+    let test_package_manifest = cu::check!(
+        cargo_toml::make_test_package_manifest(manifest_info, &test_package_name),
+        "failed to create test package manifest"
+    )?;
+    let files = vec![
+        FileDescription::new(
+            Path::new(package_name).join("Cargo.toml"),
+            &manifest_info.content,
+        ),
+        FileDescription::new(
+            Path::new(package_name).join(&manifest_info.lib_entrypoint),
+            &entryfile.produce_lib(),
+        ),
+        FileDescription::new(
+            Path::new(&test_package_name).join("Cargo.toml"),
+            &test_package_manifest,
+        ),
+    ];
+
+    let mut layers = Vec::with_capacity(dep_graph.top_down_order.len());
+    for layer in &dep_graph.top_down_order {
+        let plan = checker::plan_layer(layer, layerfile, dep_graph, entryfile)?;
+        let command = if layerfile.crate_.driver.is_empty() {
+            std::iter::once("cargo".to_string())
+                .chain(args.cargo_args.iter().cloned())
+                .collect()
+        } else {
+            layerfile.crate_.driver.clone()
+        };
+        let mut env = BTreeMap::new();
+        env.insert("LAYERED_CRATE_DEPS_LAYERS", plan.deps_str.clone());
+        env.insert("LAYERED_CRATE_TESTING_LAYER", layer.clone());
+        env.insert(crate::ACTIVE_ENV, "1".to_string());
+        if std::env::var_os("CARGO_INCREMENTAL").is_none() {
+            env.insert("CARGO_INCREMENTAL", "1".to_string());
+        }
+        if args.read_only {
+            env.insert("CARGO_TARGET_DIR", "target".to_string());
+        }
+        let layer_rust_flags = &layerfile.layer[layer].rustflags;
+        if !layer_rust_flags.is_empty() {
+            let rust_flags = util::extend_rustflags(rust_flags, layer_rust_flags);
+            env.insert("RUSTFLAGS", rust_flags);
+        } else if let Some(rust_flags) = rust_flags {
+            env.insert("RUSTFLAGS", rust_flags.to_string());
+        }
+        layers.push(LayerDescription {
+            layer: plan.layer,
+            files: vec![FileDescription::new(
+                Path::new(&test_package_name).join("lib.rs"),
+                &plan.test_file,
+            )],
+            command,
+            current_dir: test_package_name.clone(),
+            env,
+        });
+    }
+
+    Ok(RunDescription { files, layers })
+}
@@ -0,0 +1,164 @@
+use std::path::Path;
+
+use cu::pre::*;
+
+use crate::layerfile::DepGraph;
+use crate::syntax::EntryFile;
+use crate::{Options, cargo_toml, util};
+
+/// Check the environment and the configuration, and print the result of each check.
+///
+/// Returns false if any check failed
+pub fn doctor(args: &Options) -> bool {
+    let mut checklist = Checklist::default();
+
+    match crate::find_cargo().and_then(|_| cu::which("cargo")) {
+        Ok(cargo) => checklist.ok(format!("cargo found at '{}'", cargo.display())),
+        Err(e) => checklist.fail(format!("cargo not found: {e}")),
+    }
+
+    let formatter = match &args.format_cmd {
+        Some(format_cmd) => format_cmd.split_whitespace().next().unwrap_or_default(),
+        None => "rustfmt",
+    };
+    match cu::which(formatter) {
+        Ok(path) => checklist.ok(format!("{formatter} found at '{}'", path.display())),
+        Err(_) => checklist.warn(format!(
+            "{formatter} not found, the generated code will not be formatted"
+        )),
+    }
+
+    if args.no_rust_flags {
+        checklist.ok("RUSTFLAGS will not be modified".to_string());
+    } else {
+        checklist.ok("-Dunused-imports will be added to RUSTFLAGS".to_string());
+        let config_path = args
+            .manifest_dir()
+            .normalize()
+            .ok()
+            .and_then(|dir| util::find_config_with_rustflags(&dir));
+        if let Some(config_path) = config_path {
+            checklist.warn(format!(
+                "rustflags in '{}' will be ignored since RUSTFLAGS is set",
+                config_path.display()
+            ));
+        }
+    }
+
+    let layerfile_path = args.layerfile();
+    let layerfile = match args.read_layerfile() {
+        Ok(x) => {
+            checklist.ok(format!(
+                "Layerfile at '{}' is valid",
+                layerfile_path.display()
+            ));
+            Some(x)
+        }
+        Err(e) => {
+            checklist.fail(format!(
+                "failed to read Layerfile at '{}': {e}",
+                layerfile_path.display()
+            ));
+            None
+        }
+    };
+    let manifest_path = Path::new(&args.manifest_path);
+    let entry = layerfile.as_ref().and_then(|x| x.crate_.entry.as_deref());
+    let manifest_info =
+        match cargo_toml::prepare(manifest_path, args.workspace_root().as_deref(), entry) {
+            Ok(x) => {
+                checklist.ok(format!(
+                    "Cargo.toml at '{}' is valid",
+                    manifest_path.display()
+                ));
+                Some(x)
+            }
+            Err(e) => {
+                checklist.fail(format!(
+                    "failed to read Cargo.toml at '{}': {e}",
+                    manifest_path.display()
+                ));
+                None
+            }
+        };
+
+    if let Some(layerfile) = &layerfile {
+        match DepGraph::build(&layerfile.layer) {
+            Ok(graph) => checklist.ok(format!(
+                "dependency graph has {} layer(s)",
+                graph.top_down_order.len()
+            )),
+            Err(e) => checklist.fail(format!("invalid dependency graph: {e}")),
+        }
+    }
+
+    if let Some(manifest_info) = &manifest_info {
+        let entryfile_path = args.manifest_dir().join(&manifest_info.lib_entrypoint);
+        let base_path = entryfile_path.parent().unwrap_or(Path::new("."));
+        let entryfile = EntryFile::resolve(
+            &manifest_info.lib_entrypoint_content,
+            base_path,
+            None,
+            &manifest_info.test_edition,
+        );
+        match entryfile {
+            Ok(entryfile) => {
+                let modules = entryfile.all_modules();
+                let message = format!(
+                    "entry file '{}' has {} module(s)",
+                    entryfile_path.display(),
+                    modules.len()
+                );
+                if modules.is_empty() {
+                    checklist.fail(message);
+                } else {
+                    checklist.ok(message);
+                }
+                if let Some(layerfile) = &layerfile
+                    && let Err(e) = layerfile.check_declared_layers(&modules)
+                {
+                    checklist.fail(format!("invalid layer in Layerfile: {e}"));
+                }
+            }
+            Err(e) => checklist.fail(format!(
+                "failed to resolve entry file '{}': {e}",
+                entryfile_path.display()
+            )),
+        }
+    }
+
+    checklist.print()
+}
+
+#[derive(Default)]
+struct Checklist {
+    lines: Vec<String>,
+    has_warning: bool,
+    has_failure: bool,
+}
+
+impl Checklist {
+    fn ok(&mut self, message: String) {
+        self.lines.push(format!("[ OK ] {message}"));
+    }
+    fn warn(&mut self, message: String) {
+        self.has_warning = true;
+        self.lines.push(format!("[WARN] {message}"));
+    }
+    fn fail(&mut self, message: String) {
+        self.has_failure = true;
+        self.lines.push(format!("[FAIL] {message}"));
+    }
+    /// Print the checklist, returns false if any check failed
+    fn print(self) -> bool {
+        let checklist = self.lines.join("\n");
+        if self.has_failure {
+            cu::error!("some checks failed:\n{checklist}");
+        } else if self.has_warning {
+            cu::warn!("all checks passed with warning(s):\n{checklist}");
+        } else {
+            cu::info!("all checks passed:\n{checklist}");
+        }
+        !self.has_failure
+    }
+}
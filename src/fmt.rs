@@ -0,0 +1,404 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use cu::pre::*;
+use toml_edit::{Array, DocumentMut, Item, Table, Value};
+
+use crate::layerfile::{DepGraph, LayerFile};
+use crate::{Options, util};
+
+/// Rewrite the Layerfile in the canonical form: the layers in top-down order
+/// of the dependency graph, and `depends-on` of each layer in the same order.
+///
+/// The file is edited with `toml_edit`, so comments and formatting are kept
+pub fn format_layerfile(args: &Options) -> cu::Result<()> {
+    let path = args.layerfile();
+    if args.from_metadata || !path.exists() {
+        cu::bail!("only the Layerfile can be formatted, not the layers in Cargo.toml");
+    }
+    let layerfile = cu::check!(args.read_layerfile(), "failed to read Layerfile")?;
+    let dep_graph = cu::check!(
+        DepGraph::build(&layerfile.layer),
+        "failed to build dependency graph from Layerfile"
+    )?;
+    let content = cu::fs::read_string(&path)?;
+    let formatted = format_content(&content, &layer_order(&layerfile, &dep_graph))?;
+    if formatted == content {
+        cu::info!("Layerfile '{}' is already formatted", path.display());
+        return Ok(());
+    }
+    cu::fs::write(&path, formatted)?;
+    cu::info!("formatted Layerfile '{}'", path.display());
+    Ok(())
+}
+
+/// Check the Layerfile is in the canonical form, with `--check-fmt`.
+///
+/// Only the order of the layers and `depends-on` is checked, since formatting
+/// only changes the order and keeps everything else
+pub fn check_fmt(path: &Path, layerfile: &LayerFile, dep_graph: &DepGraph) -> cu::Result<()> {
+    let content = cu::fs::read_string(path)?;
+    let formatted = format_content(&content, &layer_order(layerfile, dep_graph))?;
+    if formatted == content {
+        cu::debug!("Layerfile is formatted");
+        return Ok(());
+    }
+    cu::error!(
+        "layers or depends-on in Layerfile '{}' are not in top-down order:\n{}",
+        path.display(),
+        util::unified_diff(&content, &formatted)
+    );
+    cu::hint!(
+        "the top-down order is: {}",
+        dep_graph.top_down_order.join(", ")
+    );
+    cu::hint!("run `layered-crate fmt` to format it");
+    cu::bail!("Layerfile is not formatted");
+}
+
+/// Position of each layer (and its alias) in the top-down order
+fn layer_order(layerfile: &LayerFile, dep_graph: &DepGraph) -> BTreeMap<String, usize> {
+    let mut order = BTreeMap::new();
+    for (i, name) in dep_graph.top_down_order.iter().enumerate() {
+        order.insert(name.clone(), i);
+        if let Some(alias) = layerfile.layer.get(name).and_then(|x| x.alias.as_ref()) {
+            order.insert(alias.clone(), i);
+        }
+    }
+    order
+}
+
+fn format_content(content: &str, order: &BTreeMap<String, usize>) -> cu::Result<String> {
+    let position = |name: &str| order.get(name).copied().unwrap_or(usize::MAX);
+    let mut document = cu::check!(content.parse::<DocumentMut>(), "failed to parse Layerfile")?;
+    let Some(layers) = document.get_mut("layer") else {
+        return Ok(content.to_string());
+    };
+    match layers {
+        Item::Table(table) => {
+            // `[layer]` with inline tables, or dotted keys like `layer.foo.depends-on`
+            table.sort_values_by(|a, _, b, _| position(a.get()).cmp(&position(b.get())));
+            // `[layer.foo]` and its sub-tables
+            sort_layer_tables(table, &position);
+        }
+        Item::Value(Value::InlineTable(table)) => {
+            table.sort_values_by(|a, _, b, _| position(a.get()).cmp(&position(b.get())));
+        }
+        _ => {}
+    }
+    if let Some(layers) = layers.as_table_like_mut() {
+        for (_, layer) in layers.iter_mut() {
+            let depends_on = layer
+                .as_table_like_mut()
+                .and_then(|layer| layer.get_mut("depends-on"))
+                .and_then(Item::as_array_mut);
+            if let Some(depends_on) = depends_on {
+                sort_depends_on(depends_on, &position);
+            }
+        }
+    }
+    Ok(document.to_string())
+}
+
+/// Move the tables of the layers (`[layer.foo]` and its sub-tables) to the top-down order.
+///
+/// The tables of the layers take the places where the layers were in the file, so other tables
+/// in between stay where they are. The blank lines before the tables stay in place as well,
+/// while the comments right above the tables are moved with them
+fn sort_layer_tables(layers: &mut Table, position: &impl Fn(&str) -> usize) {
+    let mut tables = Vec::new();
+    for_each_layer_table(layers, &mut |layer, table| {
+        if let Some(doc_position) = table.position() {
+            let (blank_lines, _) = split_blank_lines(decor_prefix(table));
+            tables.push((position(layer), doc_position, blank_lines.to_string()));
+        }
+    });
+    let mut places = tables
+        .iter()
+        .map(|(_, doc_position, blank_lines)| (*doc_position, blank_lines.clone()))
+        .collect::<Vec<_>>();
+    places.sort_by_key(|(doc_position, _)| *doc_position);
+    tables.sort_by_key(|(position, doc_position, _)| (*position, *doc_position));
+    let moves = tables
+        .into_iter()
+        .zip(places)
+        .map(|((_, from, _), to)| (from, to))
+        .collect::<BTreeMap<_, _>>();
+    for_each_layer_table(layers, &mut |_, table| {
+        let Some((to, blank_lines)) = table.position().and_then(|x| moves.get(&x)) else {
+            return;
+        };
+        let (_, comments) = split_blank_lines(decor_prefix(table));
+        let prefix = format!("{blank_lines}{comments}");
+        table.decor_mut().set_prefix(prefix);
+        table.set_position(Some(*to));
+    });
+}
+
+/// Call the function for every table with a header in the layers, with the name of the layer
+fn for_each_layer_table(layers: &mut Table, f: &mut impl FnMut(&str, &mut Table)) {
+    fn visit(layer: &str, table: &mut Table, f: &mut impl FnMut(&str, &mut Table)) {
+        if !table.is_implicit() && !table.is_dotted() {
+            f(layer, table);
+        }
+        for (_, item) in table.iter_mut() {
+            if let Some(table) = item.as_table_mut() {
+                visit(layer, table, f);
+            }
+        }
+    }
+    for (layer, item) in layers.iter_mut() {
+        if let Some(table) = item.as_table_mut() {
+            visit(layer.get(), table, f);
+        }
+    }
+}
+
+fn decor_prefix(table: &Table) -> &str {
+    table
+        .decor()
+        .prefix()
+        .and_then(|x| x.as_str())
+        .unwrap_or_default()
+}
+
+/// Split the text before a table header into the blank lines and the rest (comments)
+fn split_blank_lines(prefix: &str) -> (&str, &str) {
+    let end = prefix
+        .split_inclusive('\n')
+        .take_while(|line| line.trim().is_empty())
+        .map(str::len)
+        .sum();
+    prefix.split_at(end)
+}
+
+/// Sort the names in `depends-on` in the top-down order.
+///
+/// A comment after a value on the same line is moved with the value, and so are the
+/// comments on the lines above the value. The rest of the whitespace stays in place
+fn sort_depends_on(array: &mut Array, position: &impl Fn(&str) -> usize) {
+    let values = array.iter().cloned().collect::<Vec<_>>();
+    let mut order = (0..values.len()).collect::<Vec<_>>();
+    order.sort_by_key(|i| values[*i].as_str().map_or(usize::MAX, position));
+    if order.iter().enumerate().all(|(i, j)| i == *j) {
+        return;
+    }
+    // the text before each value, and before the closing bracket
+    let gaps = values
+        .iter()
+        .map(|x| {
+            x.decor()
+                .prefix()
+                .and_then(|x| x.as_str())
+                .unwrap_or_default()
+        })
+        .chain([array.trailing().as_str().unwrap_or_default()])
+        .collect::<Vec<_>>();
+    // the text until the end of the line is the comment of the previous value (if any),
+    // or whitespace that stays in place. The lines after it belong to the next value
+    let mut spaces = Vec::with_capacity(gaps.len());
+    let mut comments = vec![None; values.len()];
+    let mut above = Vec::with_capacity(gaps.len());
+    for (i, gap) in gaps.iter().enumerate() {
+        let (line, rest) = gap.split_at(gap.find('\n').unwrap_or(gap.len()));
+        if i > 0 && line.contains('#') {
+            comments[i - 1] = Some(line);
+            spaces.push("");
+        } else {
+            spaces.push(line);
+        }
+        above.push(rest);
+    }
+    let before = |i: usize| match i.checked_sub(1) {
+        None => spaces[0],
+        Some(prev) => comments[order[prev]].unwrap_or(spaces[i]),
+    };
+    let sorted = order
+        .iter()
+        .enumerate()
+        .map(|(i, j)| {
+            let mut value = values[*j].clone();
+            value
+                .decor_mut()
+                .set_prefix(format!("{}{}", before(i), above[*j]));
+            value
+        })
+        .collect::<Vec<_>>();
+    let trailing = format!("{}{}", before(values.len()), above[values.len()]);
+    array.clear();
+    for value in sorted {
+        array.push_formatted(value);
+    }
+    array.set_trailing(trailing);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(content: &str) -> String {
+        let order = [("app", 0), ("db", 1), ("util", 2)]
+            .into_iter()
+            .map(|(name, i)| (name.to_string(), i))
+            .collect();
+        format_content(content, &order).unwrap()
+    }
+
+    #[test]
+    fn test_layers_in_top_down_order() {
+        let content = r#"[layer.util]
+
+[layer.app]
+depends-on = ["util", "db"]
+
+[layer.db]
+depends-on = ["util"]
+"#;
+        let expected = r#"[layer.app]
+depends-on = ["db", "util"]
+
+[layer.db]
+depends-on = ["util"]
+
+[layer.util]
+"#;
+        assert_eq!(format(content), expected);
+        assert_eq!(format(expected), expected);
+    }
+
+    #[test]
+    fn test_multi_line_array_is_not_header() {
+        let content = r#"[crate]
+feature-matrix = [
+    [],
+    ["a", "b"], # comment
+]
+
+[layer.app]
+depends-on = ["db"]
+
+[layer.db]
+"#;
+        assert_eq!(format(content), content);
+    }
+
+    #[test]
+    fn test_multi_line_string_is_not_header() {
+        let content = r#"[crate.hints]
+"pattern" = """
+[layer.util]
+"""
+
+[layer.app]
+"#;
+        assert_eq!(format(content), content);
+    }
+
+    #[test]
+    fn test_comments_move_with_layer() {
+        let content = r#"# the crate
+[crate]
+exclude = ["bench"]
+
+# bottom layer
+[layer.util]
+
+# top layer
+# uses everything
+[layer.app]
+depends-on = ["util"]
+"#;
+        let expected = r#"# the crate
+[crate]
+exclude = ["bench"]
+
+# top layer
+# uses everything
+[layer.app]
+depends-on = ["util"]
+
+# bottom layer
+[layer.util]
+"#;
+        assert_eq!(format(content), expected);
+    }
+
+    #[test]
+    fn test_sub_tables_stay_with_layer() {
+        let content = r#"[layer.db]
+
+[layer.app]
+depends-on = ["db"]
+
+[layer.app.allow]
+db = ["Db"]
+
+[layer.util]
+"#;
+        let expected = r#"[layer.app]
+depends-on = ["db"]
+
+[layer.app.allow]
+db = ["Db"]
+
+[layer.db]
+
+[layer.util]
+"#;
+        assert_eq!(format(content), expected);
+    }
+
+    #[test]
+    fn test_multi_line_depends_on_keeps_comments() {
+        let content = r#"[layer.app]
+depends-on = [
+    "util", # helpers
+    "db",
+]
+"#;
+        let expected = r#"[layer.app]
+depends-on = [
+    "db",
+    "util", # helpers
+]
+"#;
+        assert_eq!(format(content), expected);
+    }
+
+    #[test]
+    fn test_layers_in_inline_tables() {
+        let content = r#"[layer]
+util = {}
+db = { depends-on = ["util"] } # database
+app = { depends-on = ["util", "db"] }
+"#;
+        let expected = r#"[layer]
+app = { depends-on = ["db", "util"] }
+db = { depends-on = ["util"] } # database
+util = {}
+"#;
+        assert_eq!(format(content), expected);
+        assert_eq!(format(expected), expected);
+    }
+
+    #[test]
+    fn test_layers_in_dotted_keys() {
+        let content = r#"layer.db.depends-on = ["util"]
+layer.util = {}
+layer.app.depends-on = ["util", "db"]
+"#;
+        let expected = r#"layer.app.depends-on = ["db", "util"]
+layer.db.depends-on = ["util"]
+layer.util = {}
+"#;
+        assert_eq!(format(content), expected);
+        assert_eq!(format(expected), expected);
+    }
+
+    #[test]
+    fn test_formatting_is_kept() {
+        // only the order is canonical, not the whitespace
+        let content = "[layer.app]\ndepends-on=[\"db\"]\n[layer.db]\n";
+        assert_eq!(format(content), content);
+    }
+}
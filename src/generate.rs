@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+
+use cu::pre::*;
+
+use crate::{Options, util};
+
+/// Path of the generated test, relative to the manifest directory
+const TEST_PATH: &str = "tests/layers.rs";
+
+/// Write an integration test that checks the crate by layers with the library API,
+/// so layers are checked with `cargo test`. Returns the path of the test
+pub fn generate_test(args: &Options, force: bool) -> cu::Result<PathBuf> {
+    let manifest_dir = args.manifest_dir().normalize()?;
+    let test_path = manifest_dir.join(TEST_PATH);
+    if test_path.exists() && !force {
+        cu::error!("'{}' already exists", test_path.display());
+        cu::hint!("use --force to overwrite it");
+        cu::bail!("failed to generate test");
+    }
+
+    let options = if args.from_metadata || !args.layerfile().exists() {
+        "    options.from_metadata = true;\n".to_string()
+    } else {
+        let layerfile = args.layerfile().normalize()?;
+        let Ok(layerfile) = layerfile.strip_prefix(&manifest_dir) else {
+            cu::bail!(
+                "the Layerfile '{}' must be in the directory of Cargo.toml to be referenced by the test",
+                layerfile.display()
+            );
+        };
+        let layerfile = util::to_forward_slashes(&layerfile.to_string_lossy());
+        format!(
+            "    options.layerfile = Some(concat!(env!(\"CARGO_MANIFEST_DIR\"), \"/{layerfile}\").to_string());\n"
+        )
+    };
+    let content = format!(
+        r#"//! Generated by `layered-crate generate-test`
+
+#[test]
+fn check_layers() {{
+    let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+    let mut options = layered_crate::Options::new(manifest);
+{options}    let report = layered_crate::run(options).unwrap();
+    assert!(report.is_success());
+}}
+"#
+    );
+    cu::check!(
+        cu::fs::write(&test_path, content),
+        "failed to write test to '{}'",
+        test_path.display()
+    )?;
+    Ok(test_path)
+}
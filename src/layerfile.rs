@@ -1,22 +1,50 @@
+use std::cell::RefCell;
 use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
 
 use cu::pre::*;
 
+/// Key of the Layerfile in `[package.metadata]` of Cargo.toml
+const METADATA_KEY: &str = "layered-crate";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct LayerFile {
-    #[serde(rename = "crate")]
+    /// Additional Layerfiles to merge into this one,
+    /// relative to the directory of this file
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default, rename = "crate")]
     pub crate_: LayerFileCrateSection,
     #[serde(default)]
     pub layer: BTreeMap<String, Layer>,
+    /// Resolved test modules of each layer, see [`LayerFile::get_test_modules`]
+    #[serde(skip)]
+    test_modules: RefCell<BTreeMap<String, Vec<String>>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct LayerFileCrateSection {
     /// Modules here will never be present when test building
     #[serde(default)]
     pub exclude: Vec<String>,
+    /// Command (program and args) to run for each layer instead of cargo,
+    /// in the directory of the generated test package
+    #[serde(default)]
+    pub driver: Vec<String>,
+    /// Sets of features to check each layer with, instead of the default features.
+    /// Each layer is checked once for every set
+    #[serde(default)]
+    pub feature_matrix: Vec<Vec<String>>,
+    /// Path to the entry file relative to Cargo.toml, instead of `lib.path`
+    /// in Cargo.toml (or `src/lib.rs`). The file is checked as the library
+    #[serde(default)]
+    pub entry: Option<String>,
+    /// Hints to show for errors when checking the layers, in addition to the built-in ones.
+    /// Key is a regex matched against the error message, value is the hint
+    #[serde(default)]
+    pub hints: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,48 +57,454 @@ pub struct Layer {
     /// which must be checked together
     #[serde(default, rename = "impl")]
     pub impl_: Vec<String>,
+    /// If true, no other layer can depend on this layer
+    /// (for example, the top-level `app` layer)
+    #[serde(default)]
+    pub private: bool,
+    /// If true, this layer is a utility layer that can be used by any layer
+    /// without being in its `depends-on` (opposite of `private`)
+    #[serde(default)]
+    pub public: bool,
+    /// Restrict which items of a dependency can be used by this layer,
+    /// key is the dependency, value are paths to the allowed items
+    /// in the dependency (e.g. `Foo` or `bar::Baz`)
+    #[serde(default)]
+    pub allow: BTreeMap<String, Vec<String>>,
+    /// Forbid using some items of a dependency in this layer (opposite of `allow`),
+    /// key is the dependency, value are paths to the denied items
+    #[serde(default)]
+    pub deny: BTreeMap<String, Vec<String>>,
+    /// If true, `#[cfg(test)]` items in the layer are removed when checking,
+    /// so tests are not part of the checked surface
+    #[serde(default)]
+    pub exclude_tests: bool,
+    /// Extra flags added to RUSTFLAGS only when checking this layer
+    #[serde(default)]
+    pub rustflags: Vec<String>,
+    /// Another name to reference this layer by in the Layerfile, for example
+    /// the public name if the module is re-exported with `pub use`
+    #[serde(default)]
+    pub alias: Option<String>,
+    /// Module(s) that are always available to this layer, without
+    /// being a dependency (for example, a test shim)
+    #[serde(default)]
+    pub also_include: Vec<String>,
+    /// Dependencies to glob import at the crate root of the test library, for
+    /// when the entry file re-exports them (for example, `pub use traits::*;`).
+    ///
+    /// This does not bring traits into scope in the modules of the layer,
+    /// they still need to be imported (for example, `use crate::Trait;`)
+    #[serde(default)]
+    pub glob_import: Vec<String>,
+    /// Module(s) in `crate.exclude` that are available to this layer,
+    /// like the modules in `also-include`
+    #[serde(default)]
+    pub unexclude: Vec<String>,
 }
 
 impl LayerFile {
+    /// Read the Layerfile at the path, and merge the included Layerfiles into it
+    pub fn read(path: &Path) -> cu::Result<Self> {
+        let mut visited = BTreeSet::new();
+        let mut layerfile = Self::read_recur(path, &mut visited)?;
+        cu::check!(
+            layerfile.resolve_aliases(),
+            "failed to resolve layer aliases"
+        )?;
+        Ok(layerfile)
+    }
+
+    /// Replace references to layers by their aliases with the module names
+    fn resolve_aliases(&mut self) -> cu::Result<()> {
+        let mut aliases = BTreeMap::new();
+        for (name, layer) in &self.layer {
+            let Some(alias) = &layer.alias else {
+                continue;
+            };
+            if self.layer.contains_key(alias) {
+                cu::bail!("alias `{alias}` of layer `{name}` is the same as another layer");
+            }
+            if let Some(other) = aliases.insert(alias.clone(), name.clone()) {
+                cu::bail!("alias `{alias}` is used by both layer `{other}` and `{name}`");
+            }
+        }
+        if aliases.is_empty() {
+            return Ok(());
+        }
+        cu::debug!("layer aliases: {aliases:?}");
+        let resolve = |names: &mut Vec<String>| {
+            for name in names {
+                if let Some(module) = aliases.get(name) {
+                    *name = module.clone();
+                }
+            }
+        };
+        resolve(&mut self.crate_.exclude);
+        for layer in self.layer.values_mut() {
+            resolve(&mut layer.depends_on);
+            resolve(&mut layer.impl_);
+            resolve(&mut layer.also_include);
+            resolve(&mut layer.glob_import);
+            resolve(&mut layer.unexclude);
+            layer.allow = std::mem::take(&mut layer.allow)
+                .into_iter()
+                .map(|(dep, paths)| (aliases.get(&dep).cloned().unwrap_or(dep), paths))
+                .collect();
+            layer.deny = std::mem::take(&mut layer.deny)
+                .into_iter()
+                .map(|(dep, paths)| (aliases.get(&dep).cloned().unwrap_or(dep), paths))
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Read the layers from `[package.metadata.layered-crate]` in the Cargo.toml,
+    /// with the included Layerfiles relative to the manifest. `None` if the table doesn't exist
+    pub fn read_from_metadata(manifest_path: &Path) -> cu::Result<Option<Self>> {
+        cu::debug!(
+            "reading Layerfile from metadata of {}",
+            manifest_path.display()
+        );
+        let manifest_path = manifest_path.normalize_exists()?;
+        let cargo_toml = cu::check!(
+            toml::read::<toml::Table>(cu::fs::reader(&manifest_path)?),
+            "failed to parse Cargo.toml at '{}'",
+            manifest_path.display()
+        )?;
+        let Some(table) = cargo_toml
+            .get("package")
+            .and_then(|p| p.get("metadata"))
+            .and_then(|m| m.get(METADATA_KEY))
+        else {
+            return Ok(None);
+        };
+        let mut layerfile = cu::check!(
+            toml::parse::<LayerFile>(&toml::stringify(table)?),
+            "failed to parse [package.metadata.{METADATA_KEY}] in '{}'",
+            manifest_path.display()
+        )?;
+        let mut visited = BTreeSet::from([manifest_path.clone()]);
+        layerfile.merge_includes(&manifest_path, &mut visited)?;
+        cu::check!(
+            layerfile.resolve_aliases(),
+            "failed to resolve layer aliases"
+        )?;
+        Ok(Some(layerfile))
+    }
+
+    /// Check if the Cargo.toml has layers in `[package.metadata.layered-crate]`
+    pub fn has_metadata(manifest_path: &Path) -> bool {
+        let Ok(cargo_toml) = cu::fs::read_string(manifest_path) else {
+            return false;
+        };
+        toml::parse::<toml::Table>(&cargo_toml).is_ok_and(|cargo_toml| {
+            cargo_toml
+                .get("package")
+                .and_then(|p| p.get("metadata"))
+                .and_then(|m| m.get(METADATA_KEY))
+                .is_some()
+        })
+    }
+
+    fn read_recur(path: &Path, visited: &mut BTreeSet<PathBuf>) -> cu::Result<Self> {
+        cu::debug!("reading Layerfile at {}", path.display());
+        let path = cu::check!(
+            path.normalize_exists(),
+            "failed to find Layerfile at '{}'",
+            path.display()
+        )?;
+        if !visited.insert(path.clone()) {
+            cu::bail!("Layerfile '{}' is included more than once", path.display());
+        }
+        let mut layerfile = cu::check!(
+            toml::read::<LayerFile>(cu::fs::reader(&path)?),
+            "failed to parse Layerfile at '{}'",
+            path.display()
+        )?;
+        layerfile.merge_includes(&path, visited)?;
+        Ok(layerfile)
+    }
+
+    /// Merge the Layerfiles included from the file at `path` into this one
+    fn merge_includes(&mut self, path: &Path, visited: &mut BTreeSet<PathBuf>) -> cu::Result<()> {
+        let base_path = path.parent_abs()?;
+        for include in &self.include.clone() {
+            let include_path = base_path.join(include);
+            let included = cu::check!(
+                Self::read_recur(&include_path, visited),
+                "failed to read Layerfile included from '{}'",
+                path.display()
+            )?;
+            cu::check!(
+                self.merge(included),
+                "failed to merge Layerfile '{}' into '{}'",
+                include_path.display(),
+                path.display()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Merge the crate and layer sections from another Layerfile into this one.
+    /// Errors if a layer is defined in both
+    fn merge(&mut self, other: LayerFile) -> cu::Result<()> {
+        for module in other.crate_.exclude {
+            if !self.crate_.exclude.contains(&module) {
+                self.crate_.exclude.push(module);
+            }
+        }
+        if !other.crate_.driver.is_empty() {
+            if !self.crate_.driver.is_empty() {
+                cu::bail!("crate.driver is defined in multiple Layerfiles");
+            }
+            self.crate_.driver = other.crate_.driver;
+        }
+        if !other.crate_.feature_matrix.is_empty() {
+            if !self.crate_.feature_matrix.is_empty() {
+                cu::bail!("crate.feature-matrix is defined in multiple Layerfiles");
+            }
+            self.crate_.feature_matrix = other.crate_.feature_matrix;
+        }
+        if other.crate_.entry.is_some() {
+            if self.crate_.entry.is_some() {
+                cu::bail!("crate.entry is defined in multiple Layerfiles");
+            }
+            self.crate_.entry = other.crate_.entry;
+        }
+        for (pattern, hint) in other.crate_.hints {
+            if self.crate_.hints.contains_key(&pattern) {
+                cu::bail!("hint for `{pattern}` is defined in multiple Layerfiles");
+            }
+            self.crate_.hints.insert(pattern, hint);
+        }
+        for (name, layer) in other.layer {
+            if self.layer.contains_key(&name) {
+                cu::bail!("layer `{name}` is defined in multiple Layerfiles");
+            }
+            self.layer.insert(name, layer);
+        }
+        Ok(())
+    }
+
     /// Get all modules to be put in the test library for the given layer.
+    ///
+    /// The result is cached, since it's needed multiple times for each layer
     pub fn get_test_modules(&self, layer: &str) -> cu::Result<Vec<String>> {
+        if let Some(output) = self.test_modules.borrow().get(layer) {
+            return Ok(output.clone());
+        }
+        let output = self.resolve_test_modules(layer)?;
+        self.test_modules
+            .borrow_mut()
+            .insert(layer.to_string(), output.clone());
+        Ok(output)
+    }
+
+    fn resolve_test_modules(&self, layer: &str) -> cu::Result<Vec<String>> {
         cu::debug!("getting test modules for layer `{layer}`");
         // test modules are:
         // - current layer
         // - the "impl"s of the current layer
         // - the "impl"s of the "impl"s of the current layer, ...
         let mut output = vec![layer.to_string()];
-        let mut to_add = vec![];
-        loop {
-            to_add.clear();
-            for m in &output {
-                let Some(layer) = self.layer.get(m) else {
-                    cu::bail!("unexpected: layer `{m}` not found, this is a bug");
-                };
-                for dep in &layer.impl_ {
-                    to_add.push(dep);
+        let mut seen = BTreeSet::from([layer]);
+        // each layer is only visited once, new layers are appended to the output
+        let mut i = 0;
+        while let Some(m) = output.get(i) {
+            let Some(layer) = self.layer.get(m) else {
+                cu::bail!("unexpected: layer `{m}` not found, this is a bug");
+            };
+            i += 1;
+            for dep in &layer.impl_ {
+                if seen.insert(dep) {
+                    output.push(dep.to_string());
+                }
+            }
+        }
+        cu::debug!("test modules for layer `{layer}`: {:?}", output);
+        Ok(output)
+    }
+
+    /// Check that modules in `crate.exclude` are not needed by any layer
+    pub fn check_excluded_dependencies(&self) -> cu::Result<()> {
+        cu::debug!("checking excluded modules are not dependencies");
+        let mut has_error = false;
+        for (name, layer) in &self.layer {
+            for module in &layer.unexclude {
+                if !self.crate_.exclude.contains(module) {
+                    cu::warn!("module `{module}` in unexclude of layer `{name}` is not excluded");
                 }
             }
-            let mut added = false;
-            output.reserve(to_add.len());
-            for s in &to_add {
-                if output.contains(s) {
+        }
+        for excluded in &self.crate_.exclude {
+            for (name, layer) in &self.layer {
+                if layer.unexclude.contains(excluded) {
+                    cu::debug!("module `{excluded}` is unexcluded for layer `{name}`");
                     continue;
                 }
-                added = true;
-                output.push(s.to_string());
+                if layer.depends_on.contains(excluded) {
+                    cu::error!("module `{excluded}` is excluded, but layer `{name}` depends on it");
+                    has_error = true;
+                }
+                if layer.impl_.contains(excluded) {
+                    cu::error!(
+                        "module `{excluded}` is excluded, but layer `{name}` implements from it"
+                    );
+                    has_error = true;
+                }
+                if layer.also_include.contains(excluded) {
+                    cu::error!("module `{excluded}` is excluded, but layer `{name}` includes it");
+                    has_error = true;
+                }
             }
-            if !added {
-                break;
+        }
+        if has_error {
+            cu::hint!(
+                "excluded modules are deleted when checking layers - remove them from crate.exclude, or remove the dependency on them"
+            );
+            cu::hint!(
+                "to make an excluded module available to one layer, add it to unexclude of the layer"
+            );
+            cu::bail!("excluded modules cannot be dependencies of layers");
+        }
+        Ok(())
+    }
+
+    /// Check that every declared layer is a module in the entry file
+    pub fn check_declared_layers(&self, modules: &BTreeSet<String>) -> cu::Result<()> {
+        cu::debug!("checking declared layers exist in the entry file");
+        let missing = self
+            .layer
+            .keys()
+            .filter(|name| !modules.contains(*name))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        for name in &missing {
+            cu::error!(
+                "layer `{name}` is declared, but there is no `mod {name}` in the entry file"
+            );
+        }
+        cu::hint!(
+            "the module might have been renamed or deleted - update the Layerfile to match the entry file"
+        );
+        cu::bail!(
+            "layer(s) without module: {}",
+            missing
+                .iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    /// Check that modules referenced in `crate.exclude`, `also-include` and `unexclude`
+    /// exist in the entry file. Unknown modules are warnings, or errors if `strict`.
+    ///
+    /// `depends-on` and `impl` are not checked here, since they must be declared layers
+    /// (checked when building the [`DepGraph`]), and the declared layers must exist
+    /// (checked by [`LayerFile::check_declared_layers`])
+    pub fn check_unknown_modules(
+        &self,
+        modules: &BTreeSet<String>,
+        strict: bool,
+    ) -> cu::Result<()> {
+        cu::debug!("checking modules in Layerfile exist in the entry file");
+        let mut unknown = vec![];
+        for excluded in &self.crate_.exclude {
+            if !modules.contains(excluded) {
+                unknown.push(format!("module `{excluded}` in crate.exclude"));
             }
         }
-        cu::debug!("test modules for layer `{layer}`: {:?}", output);
-        Ok(output)
+        for (name, layer) in &self.layer {
+            for dep in &layer.also_include {
+                if !modules.contains(dep) {
+                    unknown.push(format!("module `{dep}` in also-include of layer `{name}`"));
+                }
+            }
+            for dep in &layer.unexclude {
+                if !modules.contains(dep) {
+                    unknown.push(format!("module `{dep}` in unexclude of layer `{name}`"));
+                }
+            }
+        }
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        for message in &unknown {
+            if strict {
+                cu::error!("{message} does not exist");
+            } else {
+                cu::warn!("{message} does not exist");
+            }
+        }
+        cu::hint!(
+            "the module might have been renamed or deleted - update the Layerfile to match the entry file"
+        );
+        if strict {
+            cu::bail!("Layerfile references modules that do not exist");
+        }
+        Ok(())
+    }
+
+    /// Check that the `allow` and `deny` rules of each layer are for its dependencies,
+    /// and the paths are valid
+    pub fn check_allowed_paths(&self) -> cu::Result<()> {
+        cu::debug!("checking allowed and denied paths of layers");
+        let mut has_error = false;
+        for (name, layer) in &self.layer {
+            let rules = layer
+                .allow
+                .iter()
+                .map(|x| ("allows", x))
+                .chain(layer.deny.iter().map(|x| ("denies", x)));
+            for (verb, (dep, paths)) in rules {
+                if !layer.depends_on.contains(dep) {
+                    cu::error!(
+                        "layer `{name}` {verb} items from `{dep}`, but does not depend on it"
+                    );
+                    has_error = true;
+                }
+                for path in paths {
+                    if split_allowed_path(path).is_none() {
+                        cu::error!("layer `{name}` {verb} invalid path `{path}` from `{dep}`");
+                        has_error = true;
+                    }
+                }
+            }
+            for dep in layer.deny.keys() {
+                if layer.allow.contains_key(dep) {
+                    cu::error!("layer `{name}` has both allow and deny for `{dep}`");
+                    has_error = true;
+                }
+            }
+        }
+        if has_error {
+            cu::hint!(
+                "paths are relative to the dependency, for example:\n[layer.my_layer.allow]\nmy_dep = [\"Foo\", \"bar::Baz\"]\n[layer.my_layer.deny]\nother_dep = [\"internal\"]"
+            );
+            cu::bail!("invalid allowed or denied paths");
+        }
+        Ok(())
     }
 }
 
+/// Split an allowed path (e.g. `bar::Baz`) into its segments, `None` if the path is invalid
+pub fn split_allowed_path(path: &str) -> Option<Vec<&str>> {
+    let segments = path.split("::").map(str::trim).collect::<Vec<_>>();
+    let is_valid = segments.iter().all(|segment| {
+        !segment.is_empty()
+            && !segment.starts_with(|c: char| c.is_ascii_digit())
+            && segment.chars().all(|c| c == '_' || c.is_alphanumeric())
+    });
+    is_valid.then_some(segments)
+}
+
 pub struct DepGraph<'a> {
     pub deps: BTreeMap<String, &'a [String]>,
+    /// The reverse edges of `deps`, i.e. the layers that depend on each layer
+    pub dependents: BTreeMap<String, Vec<String>>,
     /// The top-down order of modules based on dependencies
     ///
     /// (i.e. the first in the list depends on modules that come after it,
@@ -83,13 +517,32 @@ impl<'a> DepGraph<'a> {
         cu::debug!("building dependency graph from layers");
 
         let mut deps = BTreeMap::new();
+        let mut dependents = BTreeMap::<String, Vec<String>>::new();
         let mut temp_deps_for_building = BTreeMap::new();
         for (name, layer) in layers {
-            cu::trace!("layer: {name} -> {:?}", layer.depends_on);
+            if layer.public {
+                cu::trace!("layer: {name} (public) -> {:?}", layer.depends_on);
+            } else {
+                cu::trace!("layer: {name} -> {:?}", layer.depends_on);
+            }
             deps.insert(name.clone(), &layer.depends_on[..]);
+            dependents.entry(name.clone()).or_default();
+            for dep in &layer.depends_on {
+                dependents
+                    .entry(dep.clone())
+                    .or_default()
+                    .push(name.clone());
+            }
             temp_deps_for_building.insert(name.clone(), layer.depends_on.clone());
         }
 
+        cu::check!(
+            check_undeclared_dependencies(layers),
+            "undeclared layer in depends-on"
+        )?;
+        cu::check!(check_private_dependencies(layers), "invalid private layer")?;
+        cu::check!(check_impl_dependencies(layers), "invalid impl layer")?;
+
         cu::check!(
             check_circular_dependencies(&deps),
             "circular dependency detected"
@@ -119,9 +572,139 @@ impl<'a> DepGraph<'a> {
 
         Ok(Self {
             deps,
+            dependents,
             top_down_order: bottom_up_order.into_iter().rev().collect(),
         })
     }
+
+    /// Get the layers that depend on the layer, directly or transitively
+    pub fn dependents_of(&self, layer: &str) -> BTreeSet<String> {
+        let mut output = BTreeSet::new();
+        let mut stack = vec![layer];
+        while let Some(curr) = stack.pop() {
+            let Some(edges) = self.dependents.get(curr) else {
+                continue;
+            };
+            for edge in edges {
+                if output.insert(edge.clone()) {
+                    stack.push(edge);
+                }
+            }
+        }
+        output
+    }
+
+    /// Check that the `depends-on` list of each layer is in the same
+    /// order as the top-down order of the graph
+    pub fn check_order(&self) -> cu::Result<()> {
+        cu::debug!("checking order of depends-on in each layer");
+        let mut unordered = vec![];
+        for (name, deps) in &self.deps {
+            let mut sorted = deps.to_vec();
+            sorted.sort_by_key(|dep| self.top_down_order.iter().position(|x| x == dep));
+            if sorted[..] != deps[..] {
+                cu::error!("depends-on of layer `{name}` is not in top-down order: {deps:?}");
+                cu::hint!("change it to:\n[layer.{name}]\ndepends-on = {sorted:?}");
+                unordered.push(name.as_str());
+            }
+        }
+        if !unordered.is_empty() {
+            cu::bail!(
+                "depends-on is out of order in layer(s): {}",
+                unordered.join(", ")
+            );
+        }
+        cu::debug!("all depends-on are in order");
+        Ok(())
+    }
+}
+
+fn check_undeclared_dependencies(layers: &BTreeMap<String, Layer>) -> cu::Result<()> {
+    let mut undeclared = BTreeSet::new();
+    for (name, layer) in layers {
+        for dep in &layer.depends_on {
+            if !layers.contains_key(dep) {
+                cu::error!("layer `{name}` depends on `{dep}`, which is not declared as a layer");
+                undeclared.insert(dep.as_str());
+            }
+        }
+    }
+    if undeclared.is_empty() {
+        cu::debug!("all dependencies are declared as layers");
+        return Ok(());
+    }
+    for dep in &undeclared {
+        cu::hint!("declare `[layer.{dep}]`, with `depends-on = []` if it has no dependencies");
+    }
+    cu::hint!(
+        "a module without a [layer] section is not a layer, even if other layers depend on it"
+    );
+    cu::bail!(
+        "undeclared layer(s): {}",
+        undeclared.into_iter().collect::<Vec<_>>().join(", ")
+    );
+}
+
+fn check_private_dependencies(layers: &BTreeMap<String, Layer>) -> cu::Result<()> {
+    let mut has_error = false;
+    for (name, layer) in layers {
+        if layer.private && layer.public {
+            cu::error!("layer `{name}` cannot be both private and public");
+            has_error = true;
+        }
+        for dep in &layer.depends_on {
+            if layers.get(dep).is_some_and(|dep| dep.private) {
+                cu::error!("layer `{name}` depends on private layer `{dep}`");
+                has_error = true;
+            }
+        }
+    }
+    if has_error {
+        cu::hint!("private layers cannot be depended on by other layers");
+        cu::bail!("invalid private layer");
+    }
+    cu::debug!("no private layer is depended on");
+    Ok(())
+}
+
+fn check_impl_dependencies(layers: &BTreeMap<String, Layer>) -> cu::Result<()> {
+    let mut undeclared = BTreeSet::new();
+    for (name, layer) in layers {
+        for dep in &layer.impl_ {
+            if !layers.contains_key(dep) {
+                cu::error!(
+                    "layer `{name}` implements from `{dep}`, which is not declared as a layer"
+                );
+                undeclared.insert(dep.as_str());
+            }
+        }
+    }
+    if !undeclared.is_empty() {
+        for dep in &undeclared {
+            cu::hint!("declare `[layer.{dep}]`, or remove it from impl");
+        }
+        cu::bail!(
+            "undeclared layer(s) in impl: {}",
+            undeclared.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+    let mut has_error = false;
+    for (name, layer) in layers {
+        for dep in &layer.impl_ {
+            if layer.depends_on.contains(dep) {
+                cu::error!("layer `{name}` both implements from and depends on `{dep}`");
+                has_error = true;
+            }
+        }
+    }
+    if has_error {
+        cu::hint!(
+            "a layer that implements from another is checked together with it, so it cannot also depend on it - remove it from either impl or depends-on"
+        );
+        cu::bail!("impl and depends-on overlap");
+    }
+    cu::debug!("no layer both implements from and depends on the same layer");
+    Ok(())
 }
 
 fn check_circular_dependencies(deps: &BTreeMap<String, &[String]>) -> cu::Result<()> {
@@ -153,8 +736,9 @@ fn check_circular_dependencies_recur<'a>(
     };
 
     for edge in *edges {
-        if stack.iter().any(|&s| s == edge) {
-            let graph = format_stack(stack, edge);
+        if let Some(start) = stack.iter().position(|&s| s == edge) {
+            // only show the cycle, not the path leading to it
+            let graph = format_stack(&stack[start..], edge);
             cu::bail!("circular dependency detected: {graph}");
         }
         stack.push(edge);
@@ -174,3 +758,51 @@ fn format_stack(stack: &[&str], next: &str) -> String {
 fn format_stack_with_no_next(stack: &[&str]) -> String {
     stack.join(" -> ")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memoized_test_modules_match() {
+        let layerfile: LayerFile = toml::parse(
+            r#"
+            [layer.api]
+            impl = ["backend", "cache"]
+            [layer.backend]
+            impl = ["storage"]
+            [layer.cache]
+            impl = ["storage"]
+            [layer.storage]
+            [layer.app]
+            depends-on = ["api"]
+            "#,
+        )
+        .unwrap();
+        for layer in ["api", "backend", "cache", "storage", "app"] {
+            let expected = layerfile.resolve_test_modules(layer).unwrap();
+            assert_eq!(layerfile.get_test_modules(layer).unwrap(), expected);
+            // second call is served from the cache
+            assert!(layerfile.test_modules.borrow().contains_key(layer));
+            assert_eq!(layerfile.get_test_modules(layer).unwrap(), expected);
+        }
+        assert_eq!(
+            layerfile.get_test_modules("api").unwrap(),
+            ["api", "backend", "cache", "storage"]
+        );
+        assert_eq!(layerfile.get_test_modules("app").unwrap(), ["app"]);
+    }
+
+    #[test]
+    fn test_undeclared_impl_is_rejected() {
+        let layerfile: LayerFile = toml::parse(
+            r#"
+            [layer.a]
+            impl = ["ghost"]
+            [layer.b]
+            "#,
+        )
+        .unwrap();
+        assert!(DepGraph::build(&layerfile.layer).is_err());
+    }
+}
@@ -0,0 +1,1008 @@
+//! Enforce internal dependencies in a Rust crate
+//!
+//! This is the library API of the `layered-crate` CLI tool, which can be used
+//! to run the layer check from code (for example, in a `#[test]`):
+//!
+//! ```rust,no_run
+//! let report = layered_crate::run_from_manifest("./Cargo.toml").unwrap();
+//! assert!(report.is_success());
+//! ```
+//!
+//! See <https://github.com/Pistonite/layered-crate>
+use cu::pre::*;
+use itertools::Itertools;
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+mod cargo_toml;
+mod checker;
+mod describe;
+mod doctor;
+mod fmt;
+mod generate;
+mod layerfile;
+mod query;
+mod since;
+mod snapshot;
+mod stats;
+mod syntax;
+mod util;
+
+use cargo_toml::CargoManifestInfo;
+use layerfile::{DepGraph, LayerFile};
+use syntax::EntryFile;
+
+pub use checker::{LayerReport, LayerStatus, Report};
+pub use doctor::doctor;
+pub use fmt::format_layerfile;
+pub use generate::generate_test;
+pub use query::check_import;
+pub use snapshot::write_snapshot;
+pub use stats::{GraphStats, MaxCount, graph_stats};
+
+/// Environment variable set for cargo when checking, so the check is not run
+/// again by the crate being checked (for example, from `build.rs`)
+pub const ACTIVE_ENV: &str = "LAYERED_CRATE_ACTIVE";
+
+/// Options for checking a crate by layers
+#[derive(clap::Args, Debug, Clone, Serialize)]
+pub struct Options {
+    /// Path to the Cargo.toml of the crate to check
+    #[clap(long, default_value = "./Cargo.toml")]
+    pub manifest_path: String,
+    /// Name of the package to check in the workspace at the manifest path.
+    /// The Cargo.toml of the package is used instead of the manifest path
+    #[clap(short, long)]
+    pub package: Option<String>,
+    /// Temporary directory to put the test package for building by layers.
+    /// Default is `target/layered-crate` next to Cargo.toml
+    #[clap(short = 'T', long)]
+    pub temp_dir: Option<String>,
+    /// Path to the Layerfile.toml. Default is `Layerfile.toml` next to Cargo.toml
+    #[clap(short = 'L', long)]
+    pub layerfile: Option<String>,
+    /// Do not look for the workspace of the crate outside of this directory.
+    /// Default is the `CARGO_WORKSPACE_DIR` environment variable if set
+    #[clap(long)]
+    pub workspace_root: Option<String>,
+
+    /// Do not edit the RUSTFLAGS environment variable.
+    ///
+    /// By default, recommended deny flags such as `-Dunused-imports` are added
+    /// if missing.
+    #[clap(long)]
+    pub no_rust_flags: bool,
+
+    /// Check that `depends-on` of each layer is sorted in the top-down order
+    /// of the dependency graph
+    #[clap(long)]
+    pub check_order: bool,
+
+    /// Check that the Layerfile is formatted (see the `fmt` subcommand), which means
+    /// the layers and `depends-on` of each layer are in top-down order
+    #[clap(long)]
+    pub check_fmt: bool,
+
+    /// Check that the dependency graph is the same as the snapshot
+    /// written by the `snapshot` subcommand
+    #[clap(long)]
+    pub check_snapshot: bool,
+
+    /// Path to the snapshot of the dependency graph.
+    /// Default is `Layerfile.snapshot.json` next to the Layerfile
+    #[clap(long)]
+    pub snapshot: Option<String>,
+
+    /// Build all layers with one cargo invocation, each layer as its own
+    /// test package.
+    ///
+    /// This reduces the overhead of running cargo for every layer for crates
+    /// with many small layers. If the batch fails or has warnings, layers are built
+    /// one by one to find the layer(s) with the errors or warnings
+    #[clap(long)]
+    pub batch: bool,
+
+    /// Treat stale entries in the Layerfile (for example, excluding a module that
+    /// does not exist) as errors instead of warnings
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Do not build the full crate before checking the layers
+    #[clap(long, alias = "skip-baseline", conflicts_with = "baseline_args")]
+    pub no_baseline: bool,
+
+    /// Do not write anything outside of the temp dir, for running on a read-only
+    /// source tree. The temp dir must be specified and outside of the crate,
+    /// and cargo puts the build output in the temp dir even if a target dir
+    /// is configured
+    #[clap(long)]
+    pub read_only: bool,
+
+    /// Args to pass to cargo when building the full crate before checking the layers,
+    /// split by whitespace (for example, `--baseline-args "check --lib"`).
+    /// Default is the same args as the layers
+    #[clap(long)]
+    pub baseline_args: Option<String>,
+
+    /// Only check layers affected by changes since the git ref (for example, `main`),
+    /// which are the layers with changed files and the layers that depend on them.
+    ///
+    /// All layers are checked if the changes cannot be determined
+    #[clap(long)]
+    pub since: Option<String>,
+
+    /// Only check layers with names matching the regex (for example, `^db_`).
+    /// Can be used with `--since` to check the affected layers that match
+    #[clap(long)]
+    pub filter: Option<String>,
+
+    /// Read the layers from `[package.metadata.layered-crate]` in Cargo.toml,
+    /// instead of the Layerfile. This is the default if the Layerfile doesn't exist
+    #[clap(long)]
+    pub from_metadata: bool,
+
+    /// Edition of the generated test package. Default is the edition of the crate
+    #[clap(long)]
+    pub test_edition: Option<String>,
+
+    /// Always enable the default features of the crate in the test package,
+    /// instead of forwarding them as features of the test package.
+    ///
+    /// Use this if a layer needs code gated by a default feature
+    /// that is not enabled by the features of the layers
+    #[clap(long)]
+    pub default_features: bool,
+
+    /// Command to format the generated source files, instead of `rustfmt` found in PATH.
+    ///
+    /// The command is split by whitespace, and the source is passed in through stdin
+    /// with `--edition <edition> --emit stdout` appended to the args,
+    /// for example `--format-cmd "rustup run nightly rustfmt"`
+    #[clap(long)]
+    pub format_cmd: Option<String>,
+
+    /// Show the full output of cargo, instead of only the diagnostics.
+    ///
+    /// Warnings cannot be detected in this mode, so layers with warnings are reported as passed
+    #[clap(long)]
+    pub verbose_cargo: bool,
+
+    /// Fail if any layer passed with warnings
+    #[clap(long)]
+    pub deny_warnings: bool,
+
+    /// Print the effective configuration (options and the merged Layerfile)
+    /// as JSON, then exit without checking
+    #[clap(long)]
+    pub print_config: bool,
+
+    /// Print the files that would be generated (with hashes of their content)
+    /// and the command to check each layer as JSON, then exit without
+    /// writing or running anything
+    #[clap(long)]
+    pub describe_run: bool,
+
+    /// Print the modules that are not checked as layers (modules not in any layer,
+    /// and excluded modules) with the reason, then exit without checking
+    #[clap(long)]
+    pub list_extra: bool,
+
+    /// Print events (layer start, layer result, and diagnostics) to stdout as they happen,
+    /// one JSON object per line, for tools to consume the progress
+    #[clap(long)]
+    pub json_logs: bool,
+
+    /// Print the difference of the generated test library of each layer from the
+    /// previous run with this flag. The test libraries are kept in the temp dir
+    #[clap(long)]
+    pub diff: bool,
+
+    /// When a layer fails, suggest changes to `depends-on` of the layer
+    /// based on the unresolved and unused imports in the errors
+    #[clap(long)]
+    pub explain_error: bool,
+
+    /// Args to pass to cargo, including the command. Default is `check --lib`
+    /// and the color flag
+    #[clap(trailing_var_arg(true))]
+    pub cargo_args: Vec<String>,
+}
+
+impl Options {
+    /// Create the default options for checking the crate at the given manifest path
+    pub fn new(manifest_path: impl Into<String>) -> Self {
+        Self {
+            manifest_path: manifest_path.into(),
+            package: None,
+            temp_dir: None,
+            layerfile: None,
+            workspace_root: None,
+            no_rust_flags: false,
+            check_order: false,
+            check_fmt: false,
+            check_snapshot: false,
+            snapshot: None,
+            batch: false,
+            strict: false,
+            no_baseline: false,
+            read_only: false,
+            baseline_args: None,
+            since: None,
+            filter: None,
+            from_metadata: false,
+            test_edition: None,
+            default_features: false,
+            format_cmd: None,
+            verbose_cargo: false,
+            deny_warnings: false,
+            print_config: false,
+            describe_run: false,
+            list_extra: false,
+            json_logs: false,
+            diff: false,
+            explain_error: false,
+            cargo_args: vec![],
+        }
+    }
+
+    /// If `package` is specified, find the package in the workspace and
+    /// change the manifest path to the Cargo.toml of the package
+    pub fn resolve_package(&mut self) -> cu::Result<()> {
+        let Some(package) = self.package.take() else {
+            return Ok(());
+        };
+        find_cargo()?;
+        cu::debug!("finding package `{package}` in workspace");
+        let (child, output) = cu::which("cargo")?
+            .command()
+            .args([
+                "metadata",
+                "--format-version",
+                "1",
+                "--no-deps",
+                "--manifest-path",
+                &self.manifest_path,
+            ])
+            .stdin_null()
+            .stdout(cu::pio::string())
+            .stderr_null()
+            .spawn()?;
+        cu::check!(
+            child.wait_nz(),
+            "failed to get workspace metadata from '{}'",
+            self.manifest_path
+        )?;
+        let metadata = json::parse::<CargoMetadata>(&output.join()??)?;
+        let Some(found) = metadata.packages.into_iter().find(|x| x.name == package) else {
+            cu::bail!(
+                "package `{package}` is not found in the workspace of '{}'",
+                self.manifest_path
+            );
+        };
+        cu::debug!("found package `{package}` at '{}'", found.manifest_path);
+        self.manifest_path = found.manifest_path;
+        Ok(())
+    }
+
+    /// Get the directory containing Cargo.toml
+    pub(crate) fn manifest_dir(&self) -> &Path {
+        Path::new(&self.manifest_path)
+            .parent()
+            .unwrap_or(Path::new("."))
+    }
+
+    /// Get the directory to stop at when looking for the workspace of the crate,
+    /// from the option or the `CARGO_WORKSPACE_DIR` environment variable
+    pub fn workspace_root(&self) -> Option<PathBuf> {
+        match &self.workspace_root {
+            Some(x) => Some(PathBuf::from(x)),
+            None => std::env::var_os("CARGO_WORKSPACE_DIR").map(PathBuf::from),
+        }
+    }
+
+    /// Get the temporary directory, resolving the default if not specified
+    pub fn temp_dir(&self) -> PathBuf {
+        match &self.temp_dir {
+            Some(x) => PathBuf::from(x),
+            None => self.manifest_dir().join("target").join("layered-crate"),
+        }
+    }
+
+    /// Check the temp dir can be used with `--read-only`
+    fn check_read_only(&self) -> cu::Result<()> {
+        let Some(temp_dir) = &self.temp_dir else {
+            cu::hint!("specify a temp dir outside of the crate, for example on a tmpfs");
+            cu::bail!("--temp-dir must be specified with --read-only");
+        };
+        let temp_dir = Path::new(temp_dir).normalize()?;
+        let manifest_dir = self.manifest_dir().normalize()?;
+        if temp_dir.starts_with(&manifest_dir) {
+            cu::bail!(
+                "temp dir '{}' is inside of the crate at '{}'",
+                temp_dir.display(),
+                manifest_dir.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Get the Layerfile path, resolving the default if not specified
+    pub fn layerfile(&self) -> PathBuf {
+        match &self.layerfile {
+            Some(x) => PathBuf::from(x),
+            None => self.manifest_dir().join("Layerfile.toml"),
+        }
+    }
+
+    /// Get the path to the snapshot of the dependency graph
+    pub fn snapshot_path(&self) -> PathBuf {
+        match &self.snapshot {
+            Some(x) => PathBuf::from(x),
+            None => self.layerfile().with_file_name("Layerfile.snapshot.json"),
+        }
+    }
+
+    /// Check if the layers are declared, either in the Layerfile,
+    /// or in `[package.metadata.layered-crate]` of Cargo.toml
+    pub fn has_layerfile(&self) -> bool {
+        if !self.from_metadata && self.layerfile().exists() {
+            return true;
+        }
+        LayerFile::has_metadata(Path::new(&self.manifest_path))
+    }
+
+    /// Read the Layerfile, or the metadata in Cargo.toml if `from_metadata` is set
+    /// or the Layerfile doesn't exist
+    pub(crate) fn read_layerfile(&self) -> cu::Result<LayerFile> {
+        let layerfile = self.layerfile();
+        if !self.from_metadata && layerfile.exists() {
+            return LayerFile::read(&layerfile);
+        }
+        match LayerFile::read_from_metadata(Path::new(&self.manifest_path))? {
+            Some(x) => Ok(x),
+            None if self.from_metadata => {
+                cu::bail!("no [package.metadata.layered-crate] found in Cargo.toml")
+            }
+            // for the error message
+            None => LayerFile::read(&layerfile),
+        }
+    }
+}
+
+fn find_cargo() -> cu::Result<()> {
+    cu::check!(
+        cu::bin::find(
+            "cargo",
+            [
+                // https://doc.rust-lang.org/cargo/reference/environment-variables.html
+                // (if we make this into a 3rd party subcommand)
+                cu::bin::from_env("CARGO"),
+                cu::bin::from_env("CARGO_BIN"),
+                cu::bin::in_PATH(),
+            ],
+        ),
+        "cannot find cargo!"
+    )?;
+    Ok(())
+}
+
+/// Warn if the RUSTFLAGS set when checking the layers would override, or be
+/// overridden by, the rustflags configured in another way
+fn check_rustflags_overridden(manifest_dir: &Path) {
+    if std::env::var_os("CARGO_ENCODED_RUSTFLAGS").is_some() {
+        cu::warn!(
+            "CARGO_ENCODED_RUSTFLAGS is set, so RUSTFLAGS (including -Dunused-imports) is ignored by cargo"
+        );
+        cu::hint!(
+            "add -Dunused-imports to CARGO_ENCODED_RUSTFLAGS, or unset it to use RUSTFLAGS instead"
+        );
+        return;
+    }
+    let Ok(manifest_dir) = manifest_dir.normalize() else {
+        return;
+    };
+    let Some(config_path) = util::find_config_with_rustflags(&manifest_dir) else {
+        cu::debug!("no cargo config sets rustflags");
+        return;
+    };
+    cu::warn!(
+        "rustflags in '{}' are ignored by cargo when checking layers, since RUSTFLAGS is set",
+        config_path.display()
+    );
+    cu::hint!(
+        "add -Dunused-imports to the rustflags in the config, and use --no-rust-flags to keep them"
+    );
+}
+
+/// Output of `cargo metadata`, only the fields we need
+#[derive(Deserialize)]
+struct CargoMetadata {
+    packages: Vec<CargoMetadataPackage>,
+}
+
+#[derive(Deserialize)]
+struct CargoMetadataPackage {
+    name: String,
+    manifest_path: String,
+}
+
+/// The configuration to print with `--print-config`
+#[derive(Serialize)]
+struct EffectiveConfig<'a> {
+    cargo: PathBuf,
+    temp_dir: PathBuf,
+    layerfile_path: PathBuf,
+    /// `None` if RUSTFLAGS is not touched
+    rust_flags: Option<&'a str>,
+    options: &'a Options,
+    layerfile: &'a LayerFile,
+}
+
+/// Check the crate at the manifest path by layers with the default options,
+/// using the `Layerfile.toml` next to the manifest.
+///
+/// Note that a failed layer is reported in the returned [`Report`]. An error
+/// is only returned if the check cannot run (for example, the Layerfile is invalid
+/// or the full crate fails to build)
+pub fn run_from_manifest(path: impl AsRef<Path>) -> cu::Result<Report> {
+    let path = path.as_ref().as_utf8()?;
+    run(Options::new(path))
+}
+
+/// Check the crate from a build script (`build.rs`), with the Layerfile at the path.
+/// Relative paths are from the current directory, which is the manifest directory
+/// when running the build script.
+///
+/// An empty report is returned if the build script is run by cargo when checking
+/// (see [`ACTIVE_ENV`]), so the check is not run recursively
+pub fn run_from_build_script(
+    manifest_dir: impl AsRef<Path>,
+    layerfile: impl AsRef<Path>,
+) -> cu::Result<Report> {
+    let mut options = Options::new(manifest_dir.as_ref().join("Cargo.toml").as_utf8()?);
+    options.layerfile = Some(layerfile.as_ref().as_utf8()?.to_string());
+    run(options)
+}
+
+/// Check the crate by layers with the options.
+///
+/// See [`run_from_manifest`]
+pub fn run(mut args: Options) -> cu::Result<Report> {
+    if std::env::var_os(ACTIVE_ENV).is_some() {
+        cu::debug!("{ACTIVE_ENV} is set, not checking recursively");
+        return Ok(Report::default());
+    }
+    if args.cargo_args.is_empty() {
+        args.cargo_args = vec![
+            "check".to_string(),
+            "--lib".to_string(),
+            cu::color_flag_eq().to_string(),
+        ];
+    } else {
+        util::add_color_flag_if_missing(&mut args.cargo_args);
+    }
+
+    let rust_flags = if args.no_rust_flags {
+        None
+    } else {
+        let mut rust_flags = std::env::var("RUSTFLAGS").unwrap_or_default();
+        util::add_rustflag_if_missing("-Dunused-imports", &mut rust_flags);
+        Some(rust_flags)
+    };
+
+    find_cargo()?;
+    args.resolve_package()?;
+    if rust_flags.is_some() {
+        check_rustflags_overridden(args.manifest_dir());
+    }
+
+    cu::debug!("parsed arguments: {args:#?}");
+    let layerfile = cu::check!(args.read_layerfile(), "failed to read Layerfile")?;
+    let manifest_path = Path::new(&args.manifest_path);
+    let mut manifest_info = cu::check!(
+        cargo_toml::prepare(
+            manifest_path,
+            args.workspace_root().as_deref(),
+            layerfile.crate_.entry.as_deref()
+        ),
+        "failed to prepare Cargo.toml"
+    )?;
+    if let Some(edition) = &args.test_edition {
+        manifest_info.test_edition = edition.clone();
+    }
+    if args.default_features {
+        manifest_info.test_default_features = true;
+        if !layerfile.crate_.feature_matrix.is_empty() {
+            cu::warn!(
+                "--default-features is set, the default features are enabled for every set in crate.feature-matrix"
+            );
+        }
+    }
+
+    if args.print_config {
+        let config = EffectiveConfig {
+            cargo: cu::which("cargo")?,
+            temp_dir: args.temp_dir(),
+            layerfile_path: args.layerfile(),
+            rust_flags: rust_flags.as_deref(),
+            options: &args,
+            layerfile: &layerfile,
+        };
+        println!("{}", json::stringify_pretty(&config)?);
+        return Ok(Report::default());
+    }
+    cu::check!(
+        layerfile.check_excluded_dependencies(),
+        "invalid exclude in Layerfile"
+    )?;
+    cu::check!(
+        layerfile.check_allowed_paths(),
+        "invalid allow or deny in Layerfile"
+    )?;
+
+    let dep_graph = cu::check!(
+        DepGraph::build(&layerfile.layer),
+        "failed to build dependency graph from Layerfile"
+    )?;
+    if args.check_order {
+        cu::check!(
+            dep_graph.check_order(),
+            "order of dependencies in Layerfile is not canonical"
+        )?;
+    }
+    if args.check_fmt {
+        let layerfile_path = args.layerfile();
+        if args.from_metadata || !layerfile_path.exists() {
+            cu::warn!("--check-fmt only checks the Layerfile, not the layers in Cargo.toml");
+        } else {
+            cu::check!(
+                fmt::check_fmt(&layerfile_path, &layerfile, &dep_graph),
+                "order of layers in Layerfile is not canonical"
+            )?;
+        }
+    }
+    if args.check_snapshot {
+        cu::check!(
+            snapshot::check_snapshot(&args.snapshot_path(), &dep_graph),
+            "dependency graph changed"
+        )?;
+    }
+
+    let entryfile_path = args.manifest_dir().join(&manifest_info.lib_entrypoint);
+    let entryfile_base_path = cu::check!(
+        entryfile_path.parent(),
+        "failed to determine base path for entry file"
+    )?;
+    let entryfile = cu::check!(
+        EntryFile::resolve(
+            &manifest_info.lib_entrypoint_content,
+            entryfile_base_path,
+            args.format_cmd.clone(),
+            &manifest_info.test_edition
+        ),
+        "Failed to resolve modules in library entry file"
+    )?;
+
+    let all_modules = entryfile.all_modules();
+    if all_modules.is_empty() {
+        cu::error!(
+            "no modules found in the entry file '{}'",
+            entryfile_path.display()
+        );
+        cu::hint!(
+            "layers are the top-level modules of the library - check that `lib.path` in Cargo.toml (or `crate.entry` in the Layerfile) points to the right file"
+        );
+        cu::bail!("no modules to check");
+    }
+    cu::check!(
+        layerfile.check_declared_layers(&all_modules),
+        "invalid layer in Layerfile"
+    )?;
+    cu::check!(
+        layerfile.check_unknown_modules(&all_modules, args.strict),
+        "invalid module in Layerfile"
+    )?;
+    let reexports = entryfile.find_reexports(&layerfile.crate_.exclude);
+    for (module, path) in &reexports {
+        cu::warn!("excluded module `{module}` is re-exported in the entry file: `pub use {path}`");
+    }
+    if !reexports.is_empty() {
+        cu::hint!(
+            "excluded modules are not checked, so the re-exported items are public without following the layers"
+        );
+    }
+
+    if layerfile.layer.is_empty() {
+        // nothing would be checked other than building the crate,
+        // which is most likely a misconfiguration
+        cu::warn!("no layers are declared in the Layerfile");
+        let modules = entryfile.all_modules();
+        if !modules.is_empty() {
+            let tables = modules.iter().map(|m| format!("[layer.{m}]")).join("\n");
+            cu::hint!("declare the modules in the entry file as layers, for example:\n{tables}");
+        }
+        cu::bail!("no layers to check");
+    }
+
+    if args.list_extra {
+        let extra_modules = checker::list_extra_modules(&layerfile, &dep_graph, &entryfile);
+        if extra_modules.is_empty() {
+            cu::info!("all modules are checked as layers");
+        }
+        for (module, reason) in extra_modules {
+            println!("{module}: {reason}");
+        }
+        return Ok(Report::default());
+    }
+
+    if args.describe_run {
+        let description = describe::describe_run(
+            &args,
+            rust_flags.as_deref(),
+            &manifest_info,
+            &layerfile,
+            &dep_graph,
+            &entryfile,
+        )?;
+        println!("{}", json::stringify_pretty(&description)?);
+        return Ok(Report::default());
+    }
+
+    if args.read_only {
+        cu::check!(args.check_read_only(), "cannot check in read-only mode")?;
+    }
+    let temp_dir = args.temp_dir();
+    let batch_layers: &[String] = if args.batch {
+        &dep_graph.top_down_order
+    } else {
+        &[]
+    };
+    cu::check!(
+        prepare_workspace(
+            &temp_dir,
+            args.manifest_dir(),
+            &manifest_info,
+            &entryfile,
+            batch_layers,
+            util::is_offline(&args.cargo_args),
+        ),
+        "failed to prepare temporary workspace"
+    )?;
+    // cargo configs are discovered from the current directory, so the ones
+    // for the crate might not apply if the temp dir is somewhere else
+    let temp_dir_abs = temp_dir.normalize()?;
+    let temp_dir_configs = util::find_cargo_configs(&temp_dir_abs);
+    for config in util::find_cargo_configs(&args.manifest_dir().normalize()?) {
+        if !temp_dir_configs.contains(&config) {
+            cu::warn!(
+                "cargo config '{}' does not apply to the temp dir '{}'",
+                config.display(),
+                temp_dir_abs.display()
+            );
+            cu::hint!("use a temp dir inside the project, or copy the config to the temp dir");
+        }
+    }
+    if util::is_locked(&args.cargo_args) && manifest_info.lockfile_path.is_some() {
+        cu::check!(
+            sync_lockfile(&temp_dir, util::is_offline(&args.cargo_args)),
+            "failed to add the generated packages to Cargo.lock"
+        )?;
+    }
+
+    let test_package_name = util::test_package_name(&manifest_info.package_name);
+    let package_dir = temp_dir.join(&manifest_info.package_name);
+
+    cu::debug!("start layer testing");
+
+    let mut only_layers = args.since.as_deref().and_then(|since| {
+        since::affected_layers(
+            since,
+            args.manifest_dir(),
+            &layerfile,
+            &dep_graph,
+            &entryfile,
+        )
+    });
+    if let Some(only_layers) = &only_layers {
+        cu::info!(
+            "checking {} of {} layer(s) affected by changes",
+            only_layers.len(),
+            dep_graph.top_down_order.len()
+        );
+    }
+    if let Some(filter) = &args.filter {
+        let regex = cu::check!(regex::Regex::new(filter), "invalid regex for --filter")?;
+        let matched = dep_graph
+            .top_down_order
+            .iter()
+            .filter(|layer| regex.is_match(layer))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        let matched = match only_layers {
+            Some(only_layers) => only_layers.intersection(&matched).cloned().collect(),
+            None => matched,
+        };
+        if matched.is_empty() {
+            cu::warn!("no layer to check matches `{filter}`");
+        } else {
+            cu::info!(
+                "checking {} of {} layer(s) matching `{filter}`",
+                matched.len(),
+                dep_graph.top_down_order.len()
+            );
+        }
+        only_layers = Some(matched);
+    }
+
+    let report = cu::check!(
+        checker::build_by_layers(
+            &args,
+            rust_flags.as_deref(),
+            manifest_path,
+            &temp_dir,
+            &package_dir,
+            &test_package_name,
+            &layerfile,
+            &dep_graph,
+            &entryfile,
+            only_layers.as_ref(),
+        ),
+        "layer test failed"
+    )?;
+
+    checker::print_summary(&report, &dep_graph.top_down_order);
+    let warned = report.warned_layers().unique().collect::<Vec<_>>();
+    if !warned.is_empty() {
+        cu::warn!("layer(s) passed with warning(s): {}", warned.join(", "));
+        if args.deny_warnings {
+            cu::bail!("warnings are denied with --deny-warnings");
+        }
+    }
+    cu::debug!("layer testing completed");
+    Ok(report)
+}
+
+/// Add the generated packages to the Cargo.lock copied to the workspace,
+/// without changing the locked versions of the dependencies.
+///
+/// This is needed for `--locked`, since cargo fails if the lock file needs to be updated
+fn sync_lockfile(temp_dir: &Path, offline: bool) -> cu::Result<()> {
+    cu::debug!("updating workspace packages in Cargo.lock");
+    let mut command = cu::which("cargo")?
+        .command()
+        .args(["update", "--workspace"])
+        .current_dir(temp_dir);
+    if offline {
+        command = command.arg("--offline");
+    }
+    let child = command.stdin_null().stdoe_null().spawn()?;
+    if let Err(e) = child.wait_nz() {
+        cu::hint!(
+            "try running `cargo update --workspace` in '{}' to see the error",
+            temp_dir.display()
+        );
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn prepare_workspace(
+    temp_dir: &Path,
+    manifest_dir: &Path,
+    manifest_info: &CargoManifestInfo,
+    entryfile: &EntryFile,
+    batch_layers: &[String],
+    offline: bool,
+) -> cu::Result<()> {
+    cu::debug!("preparing workspace");
+    let path = temp_dir;
+
+    let package_name = &manifest_info.package_name;
+    let package_dir = path.join(package_name);
+    cu::check!(
+        cu::fs::make_dir(&package_dir),
+        "failed to create temporary package directory"
+    )?;
+
+    cu::debug!("ensuring test package directory exists");
+    let test_package_name = util::test_package_name(&manifest_info.package_name);
+    let test_package_dir = path.join(&test_package_name);
+    cu::check!(
+        cu::fs::make_dir(&test_package_dir),
+        "failed to create test package directory"
+    )?;
+
+    let batch_package_dirs = batch_layers
+        .iter()
+        .map(|layer| {
+            let name = util::batch_test_package_name(&test_package_name, layer);
+            (path.join(&name), name)
+        })
+        .collect::<Vec<_>>();
+    for (dir, _) in &batch_package_dirs {
+        cu::check!(
+            cu::fs::make_dir(dir),
+            "failed to create batch test package directory"
+        )?;
+    }
+
+    let build_script = manifest_dir.join("build.rs");
+    if build_script.exists() && !build_script.is_dir() {
+        cu::debug!("found build script, copying build script to generated packages");
+        let package_build_script = package_dir.join("build.rs");
+        cu::check!(
+            cu::fs::copy(&build_script, package_build_script),
+            "failed to copy build script to temporary package"
+        )?;
+        let test_package_build_script = test_package_dir.join("build.rs");
+        cu::check!(
+            cu::fs::copy(&build_script, test_package_build_script),
+            "failed to copy build script to test package"
+        )?;
+        for (dir, _) in &batch_package_dirs {
+            cu::check!(
+                cu::fs::copy(&build_script, dir.join("build.rs")),
+                "failed to copy build script to batch test package"
+            )?;
+        }
+    }
+
+    cu::debug!("writing Cargo.toml to package directory");
+    let cargo_toml_path = package_dir.join("Cargo.toml");
+    cu::check!(
+        cu::fs::write(&cargo_toml_path, &manifest_info.content),
+        "failed to write modified Cargo.toml to temporary package directory"
+    )?;
+
+    cu::debug!("preparing workspace Cargo.toml");
+    let workspace_cargo_toml_path = path.join("Cargo.toml");
+    let cargo_toml_string = if workspace_cargo_toml_path.exists() {
+        cu::trace!(
+            "reading existing workspace Cargo.toml at {}",
+            workspace_cargo_toml_path.display()
+        );
+        match cu::fs::read_string(&workspace_cargo_toml_path) {
+            Ok(content) => {
+                cu::trace!("read existing workspace Cargo.toml content");
+                content
+            }
+            Err(e) => {
+                cu::warn!("failed to read existing workspace Cargo.toml: {e}, creating new one");
+                "[workspace]".to_string()
+            }
+        }
+    } else {
+        cu::trace!("no existing workspace Cargo.toml found, creating new one");
+        "[workspace]".to_string()
+    };
+    let mut workspace_cargo_toml = match toml::parse::<toml::Table>(&cargo_toml_string) {
+        Ok(table) => table,
+        Err(e) => {
+            cu::error!("failed to parse existing workspace Cargo.toml: {e}");
+            Default::default()
+        }
+    };
+    let workspace = workspace_cargo_toml
+        .entry("workspace")
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    let workspace = match workspace.as_table_mut() {
+        Some(table) => table,
+        None => {
+            *workspace = toml::Value::Table(toml::Table::new());
+            workspace
+                .as_table_mut()
+                .expect("Failed to create workspace table")
+        }
+    };
+    // note that [workspace.package] and other tables of the crate's workspace are
+    // not copied here, since the fields inherited from them (`*.workspace = true`)
+    // are resolved in the copied manifests instead (see `cargo_toml::prepare`)
+
+    // mirror the resolver of the crate, so features are unified the same way
+    workspace.insert(
+        "resolver".to_string(),
+        toml::Value::String(manifest_info.resolver.clone()),
+    );
+
+    // only the packages generated by this tool are members, since the temp dir
+    // could have other directories (for example, if set with `-T`)
+    let mut generated = vec![package_name.as_str(), test_package_name.as_str()];
+    generated.extend(batch_package_dirs.iter().map(|(_, name)| name.as_str()));
+    let mut members = vec![];
+    for name in generated {
+        if cargo_toml::manifest_has_workspace(&path.join(name).join("Cargo.toml")) {
+            cu::debug!("not adding '{name}' as member since it declares its own workspace");
+            continue;
+        }
+        members.push(name.to_string());
+    }
+    cu::debug!("setting members of workspace: {:?}", members);
+    workspace.insert(
+        "members".to_string(),
+        toml::Value::Array(members.into_iter().map(toml::Value::String).collect()),
+    );
+
+    // [patch] and [replace] of the crate's workspace (or the crate), which
+    // could be overwritten by the user's edits if the temp dir is reused
+    for (key, value) in &manifest_info.resolved_patch {
+        cu::debug!("adding [{key}] to workspace Cargo.toml");
+        workspace_cargo_toml.insert(key.clone(), value.clone());
+    }
+
+    let workspace_serialized = cu::check!(
+        toml::stringify(&workspace_cargo_toml),
+        "failed to serialize workspace Cargo.toml"
+    )?;
+    cu::trace!("serialized workspace Cargo.toml: {workspace_serialized}");
+    cu::check!(
+        cu::fs::write(workspace_cargo_toml_path, workspace_serialized),
+        "failed to write workspace Cargo.toml"
+    )?;
+
+    // use the same locked versions of dependencies as the crate,
+    // which is also required when offline, since nothing new can be fetched
+    match &manifest_info.lockfile_path {
+        Some(lockfile_path) => {
+            cu::debug!("copying Cargo.lock to workspace");
+            cu::check!(
+                cu::fs::copy(lockfile_path, path.join("Cargo.lock")),
+                "failed to copy Cargo.lock to workspace"
+            )?;
+        }
+        None if offline => {
+            cu::warn!("no Cargo.lock found for the crate, offline build might fail");
+        }
+        None => {
+            cu::debug!("no Cargo.lock found for the crate, dependencies will be resolved by cargo");
+        }
+    }
+
+    let lib_entry_path = package_dir.join(&manifest_info.lib_entrypoint);
+    if let Some(lib_parent) = lib_entry_path.parent() {
+        cu::check!(
+            cu::fs::make_dir(lib_parent),
+            "failed to create directory for lib entry point"
+        )?;
+    }
+    cu::debug!(
+        "writing lib entry point file to: {}",
+        lib_entry_path.display()
+    );
+    let lib_content = entryfile.produce_lib();
+    cu::check!(
+        cu::fs::write(&lib_entry_path, lib_content),
+        "failed to write lib entry point file"
+    )?;
+
+    cu::debug!("preparing test package");
+
+    let test_package_manifest = cu::check!(
+        cargo_toml::make_test_package_manifest(manifest_info, &test_package_name),
+        "failed to create test package manifest"
+    )?;
+
+    let test_package_manifest_path = test_package_dir.join("Cargo.toml");
+    cu::debug!(
+        "writing test package Cargo.toml to: {}",
+        test_package_manifest_path.display()
+    );
+    cu::check!(
+        cu::fs::write(&test_package_manifest_path, test_package_manifest),
+        "failed to write test package Cargo.toml"
+    )?;
+
+    for (dir, name) in &batch_package_dirs {
+        let manifest = cu::check!(
+            cargo_toml::make_test_package_manifest(manifest_info, name),
+            "failed to create batch test package manifest"
+        )?;
+        cu::check!(
+            cu::fs::write(dir.join("Cargo.toml"), manifest),
+            "failed to write batch test package Cargo.toml"
+        )?;
+    }
+
+    cu::debug!("workspace prepared successfully");
+    Ok(())
+}
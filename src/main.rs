@@ -1,17 +1,15 @@
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use clap::Parser;
 use cu::pre::*;
 
-use std::path::Path;
-
-mod cargo_toml;
-mod checker;
-mod layerfile;
-mod syntax;
-mod util;
+/// Exit code when the Layerfile is not found
+const EXIT_NO_LAYERFILE: u8 = 2;
 
-use cargo_toml::CargoManifestInfo;
-use layerfile::{DepGraph, LayerFile};
-use syntax::EntryFile;
+/// Set when failing because the Layerfile is not found, since the `cu::cli` wrapper
+/// only exits with 0 or 1
+static NO_LAYERFILE: AtomicBool = AtomicBool::new(false);
 
 /// Enforce internal dependencies in a Rust crate
 ///
@@ -19,281 +17,125 @@ use syntax::EntryFile;
 #[derive(Parser, Debug, Clone)]
 #[clap(version)]
 struct Cli {
-    /// Temporary directory to put the test package for building by layers
-    #[clap(short = 'T', long, default_value = "./target/layered-crate")]
-    temp_dir: String,
-    /// Path to the Layerfile.toml
-    #[clap(short = 'L', long, default_value = "./Layerfile.toml")]
-    layerfile: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
 
-    /// Do not edit the RUSTFLAGS environment variable.
-    ///
-    /// By default, recommended deny flags such as `-Dunused-imports` are added
-    /// if missing.
-    #[clap(long)]
-    no_rust_flags: bool,
+    #[clap(flatten)]
+    options: layered_crate::Options,
 
     #[clap(flatten)]
     common: cu::cli::Flags,
-    /// Args to pass to cargo, including the command. Default is `check --lib`
-    /// and the color flag
-    #[clap(trailing_var_arg(true))]
-    cargo_args: Vec<String>,
 }
 
-#[cu::cli(flags = "common")]
-fn main(mut args: Cli) -> cu::Result<()> {
-    if args.cargo_args.is_empty() {
-        args.cargo_args = vec![
-            "check".to_string(),
-            "--lib".to_string(),
-            cu::color_flag_eq().to_string(),
-        ];
-    } else {
-        let mut found_color_flag = false;
-        for arg in &args.cargo_args {
-            if arg.starts_with("--color") {
-                found_color_flag = true;
-            }
-        }
-        if !found_color_flag {
-            args.cargo_args.push(cu::color_flag_eq().to_string());
-        }
-    }
-
-    if !args.no_rust_flags {
-        let mut rust_flags = std::env::var("RUSTFLAGS").unwrap_or_default();
-        util::add_rustflag_if_missing("-Dunused-imports", &mut rust_flags);
-        // safety: no other threads exist at this point
-        unsafe { std::env::set_var("RUSTFLAGS", rust_flags) };
-    }
-
-    cu::check!(
-        cu::bin::find(
-            "cargo",
-            [
-                // https://doc.rust-lang.org/cargo/reference/environment-variables.html
-                // (if we make this into a 3rd party subcommand)
-                cu::bin::from_env("CARGO"),
-                cu::bin::from_env("CARGO_BIN"),
-                cu::bin::in_PATH(),
-            ],
-        ),
-        "cannot find cargo!"
-    )?;
-
-    cu::debug!("parsed arguments: {args:#?}");
-    let manifest_path = Path::new("./Cargo.toml");
-    let manifest_info = cu::check!(
-        cargo_toml::prepare(manifest_path),
-        "failed to prepare Cargo.toml"
-    )?;
-
-    let layerfile = toml::read::<LayerFile>(cu::fs::reader(&args.layerfile)?)?;
-
-    let dep_graph = cu::check!(
-        DepGraph::build(&layerfile.layer),
-        "failed to build dependency graph from Layerfile"
-    )?;
-
-    let entryfile_path = cu::check!(
-        manifest_path
-            .parent()
-            .map(|p| p.join(&manifest_info.lib_entrypoint)),
-        "failed to determine entry file path"
-    )?;
-    let entryfile_base_path = cu::check!(
-        entryfile_path.parent(),
-        "failed to determine base path for entry file"
-    )?;
-    let entryfile = cu::check!(
-        EntryFile::resolve(&manifest_info.lib_entrypoint_content, entryfile_base_path),
-        "Failed to resolve modules in library entry file"
-    )?;
-
-    cu::check!(
-        prepare_workspace(&args.temp_dir, &manifest_info, &entryfile),
-        "failed to prepare temporary workspace"
-    )?;
-
-    let test_package_name = util::test_package_name(&manifest_info.package_name);
-    let temp_dir = Path::new(&args.temp_dir);
-    let package_dir = temp_dir.join(&manifest_info.package_name);
-    let test_package_dir = temp_dir.join(&test_package_name);
-
-    cu::debug!("start layer testing");
-
-    cu::check!(
-        checker::build_by_layers(
-            &args,
-            manifest_path,
-            &package_dir,
-            &test_package_dir,
-            &layerfile,
-            &dep_graph,
-            &entryfile,
-        ),
-        "layer test failed"
-    )?;
-
-    cu::debug!("layer testing completed successfully");
-    Ok(())
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Command {
+    /// Check if a layer is allowed to import a path by the Layerfile, without building.
+    ///
+    /// Exits with 0 if allowed, and non-zero if not allowed
+    CheckImport {
+        /// The layer that imports the path
+        layer: String,
+        /// The path to import, for example `crate::foo::Bar`
+        path: String,
+    },
+    /// Check the environment and configuration (cargo, rustfmt, Cargo.toml,
+    /// Layerfile and the entry file), and print the result of each check
+    Doctor,
+    /// Format the Layerfile, putting the layers and `depends-on` of each layer
+    /// in top-down order. Comments are kept
+    Fmt,
+    /// Generate `tests/layers.rs` that checks the layers with the library API,
+    /// so they are checked by `cargo test`
+    GenerateTest {
+        /// Overwrite the test if it already exists
+        #[clap(long)]
+        force: bool,
+    },
+    /// Write the dependency graph to the snapshot file (see `--snapshot`),
+    /// to be checked with `--check-snapshot`
+    Snapshot,
+    /// Print metrics of the dependency graph: the number of layers and edges,
+    /// the longest dependency chain, and the layers with the most dependencies and dependents
+    Stats {
+        /// Print the metrics as JSON
+        #[clap(long)]
+        json: bool,
+    },
 }
 
-fn prepare_workspace(
-    temp_dir: &str,
-    manifest_info: &CargoManifestInfo,
-    entryfile: &EntryFile,
-) -> cu::Result<()> {
-    cu::debug!("preparing workspace");
-    let path = Path::new(temp_dir);
-
-    let package_name = &manifest_info.package_name;
-    let package_dir = path.join(package_name);
-    cu::check!(
-        cu::fs::make_dir(&package_dir),
-        "failed to create temporary package directory"
-    )?;
-
-    cu::debug!("ensuring test package directory exists");
-    let test_package_name = util::test_package_name(&manifest_info.package_name);
-    let test_package_dir = path.join(&test_package_name);
-    cu::check!(
-        cu::fs::make_dir(&test_package_dir),
-        "failed to create test package directory"
-    )?;
-
-    let build_script = Path::new("./build.rs");
-    if build_script.exists() && !build_script.is_dir() {
-        cu::debug!("found build script, copying build script to generated packages");
-        let package_build_script = package_dir.join("build.rs");
-        cu::check!(
-            cu::fs::copy(build_script, package_build_script),
-            "failed to copy build script to temporary package"
-        )?;
-        let test_package_build_script = test_package_dir.join("build.rs");
-        cu::check!(
-            cu::fs::copy(build_script, test_package_build_script),
-            "failed to copy build script to test package"
-        )?;
+fn main() -> ExitCode {
+    // the exit code is changed after `run` returns, so the cli is torn down normally
+    let code = run();
+    if NO_LAYERFILE.load(Ordering::Relaxed) {
+        return ExitCode::from(EXIT_NO_LAYERFILE);
     }
+    code
+}
 
-    cu::debug!("writing Cargo.toml to package directory");
-    let cargo_toml_path = package_dir.join("Cargo.toml");
-    cu::check!(
-        cu::fs::write(&cargo_toml_path, &manifest_info.content),
-        "failed to write modified Cargo.toml to temporary package directory"
-    )?;
-
-    cu::debug!("preparing workspace Cargo.toml");
-    let workspace_cargo_toml_path = path.join("Cargo.toml");
-    let cargo_toml_string = if workspace_cargo_toml_path.exists() {
-        cu::trace!(
-            "reading existing workspace Cargo.toml at {}",
-            workspace_cargo_toml_path.display()
+#[cu::cli(flags = "common")]
+fn run(mut args: Cli) -> cu::Result<()> {
+    if std::env::var_os(layered_crate::ACTIVE_ENV).is_some() {
+        cu::info!(
+            "not running since layered-crate is already checking this crate ({} is set)",
+            layered_crate::ACTIVE_ENV
         );
-        match cu::fs::read_string(&workspace_cargo_toml_path) {
-            Ok(content) => {
-                cu::trace!("read existing workspace Cargo.toml content");
-                content
-            }
-            Err(e) => {
-                cu::warn!("failed to read existing workspace Cargo.toml: {e}, creating new one");
-                "[workspace]".to_string()
-            }
-        }
-    } else {
-        cu::trace!("no existing workspace Cargo.toml found, creating new one");
-        "[workspace]".to_string()
-    };
-    let mut workspace_cargo_toml = match toml::parse::<toml::Table>(&cargo_toml_string) {
-        Ok(table) => table,
-        Err(e) => {
-            cu::error!("failed to parse existing workspace Cargo.toml: {e}");
-            Default::default()
-        }
-    };
-    let workspace = workspace_cargo_toml
-        .entry("workspace")
-        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
-    let workspace = match workspace.as_table_mut() {
-        Some(table) => table,
-        None => {
-            *workspace = toml::Value::Table(toml::Table::new());
-            workspace
-                .as_table_mut()
-                .expect("Failed to create workspace table")
+        cu::hint!("this can happen if the check is run by a build script of the crate");
+        return Ok(());
+    }
+    args.options.resolve_package()?;
+    if let Some(Command::Doctor) = &args.command {
+        if !layered_crate::doctor(&args.options) {
+            cu::bail!("some checks failed");
         }
-    };
-    workspace
-        .entry("resolver")
-        .or_insert(toml::Value::String("2".to_string()));
+        return Ok(());
+    }
+    if !args.options.has_layerfile() {
+        let layerfile = args.options.layerfile();
+        cu::hint!(
+            "create a Layerfile.toml next to Cargo.toml to declare the layers, or specify the path with --layerfile"
+        );
+        cu::hint!(
+            "the layers can also be declared in [package.metadata.layered-crate] in Cargo.toml"
+        );
+        cu::hint!("see https://github.com/Pistonite/layered-crate for how to write the Layerfile");
+        NO_LAYERFILE.store(true, Ordering::Relaxed);
+        cu::bail!("no Layerfile.toml found at '{}'", layerfile.display());
+    }
 
-    let readdir = cu::check!(
-        cu::fs::read_dir(temp_dir),
-        "failed to read temporary directory"
-    )?;
-    let mut members = vec![];
-    for entry in readdir {
-        let entry = entry?;
-        let entry_path = entry.path();
-        if entry_path.is_dir() && entry.file_name() != "target" {
-            let manifest_path = entry_path.join("Cargo.toml");
-            if !cargo_toml::manifest_has_workspace(&manifest_path) {
-                members.push(entry.file_name().to_string_lossy().to_string());
-            }
+    if let Some(Command::CheckImport { layer, path }) = &args.command {
+        if !layered_crate::check_import(&args.options, layer, path)? {
+            cu::bail!("`{path}` cannot be imported in layer `{layer}`");
         }
+        return Ok(());
     }
-    cu::debug!("setting members of workspace: {:?}", members);
-    workspace.insert(
-        "members".to_string(),
-        toml::Value::Array(members.into_iter().map(toml::Value::String).collect()),
-    );
-
-    let workspace_serialized = cu::check!(
-        toml::stringify(&workspace_cargo_toml),
-        "failed to serialize workspace Cargo.toml"
-    )?;
-    cu::trace!("serialized workspace Cargo.toml: {workspace_serialized}");
-    cu::check!(
-        cu::fs::write(workspace_cargo_toml_path, workspace_serialized),
-        "failed to write workspace Cargo.toml"
-    )?;
-
-    let lib_entry_path = package_dir.join(&manifest_info.lib_entrypoint);
-    if let Some(lib_parent) = lib_entry_path.parent() {
-        cu::check!(
-            cu::fs::make_dir(lib_parent),
-            "failed to create directory for lib entry point"
-        )?;
+    if let Some(Command::Fmt) = &args.command {
+        return layered_crate::format_layerfile(&args.options);
+    }
+    if let Some(Command::Snapshot) = &args.command {
+        let path = layered_crate::write_snapshot(&args.options)?;
+        cu::info!("wrote snapshot to '{}'", path.display());
+        return Ok(());
+    }
+    if let Some(Command::Stats { json }) = &args.command {
+        let stats = layered_crate::graph_stats(&args.options)?;
+        if *json {
+            println!("{}", json::stringify_pretty(&stats)?);
+        } else {
+            println!("{stats}");
+        }
+        return Ok(());
+    }
+    if let Some(Command::GenerateTest { force }) = &args.command {
+        let test_path = layered_crate::generate_test(&args.options, *force)?;
+        cu::info!("generated test at '{}'", test_path.display());
+        cu::hint!("add layered-crate to [dev-dependencies] in Cargo.toml for the test to compile");
+        return Ok(());
     }
-    cu::debug!(
-        "writing lib entry point file to: {}",
-        lib_entry_path.display()
-    );
-    let lib_content = entryfile.produce_lib();
-    cu::check!(
-        cu::fs::write(&lib_entry_path, lib_content),
-        "failed to write lib entry point file"
-    )?;
-
-    cu::debug!("preparing test package");
-
-    let test_package_manifest = cu::check!(
-        cargo_toml::make_test_package_manifest(manifest_info, &test_package_name),
-        "failed to create test package manifest"
-    )?;
-
-    let test_package_manifest_path = test_package_dir.join("Cargo.toml");
-    cu::debug!(
-        "writing test package Cargo.toml to: {}",
-        test_package_manifest_path.display()
-    );
-    cu::check!(
-        cu::fs::write(&test_package_manifest_path, test_package_manifest),
-        "failed to write test package Cargo.toml"
-    )?;
 
-    cu::debug!("workspace prepared successfully");
+    let report = layered_crate::run(args.options)?;
+    if !report.is_success() {
+        let failed = report.failed_layers().collect::<Vec<_>>();
+        cu::bail!("layer test failed: {}", failed.join(", "));
+    }
     Ok(())
 }
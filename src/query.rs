@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use cu::pre::*;
+
+use crate::layerfile::{self, DepGraph};
+use crate::syntax::EntryFile;
+use crate::{Options, cargo_toml};
+
+/// Check if the layer is allowed to import the path (for example, `crate::foo::Bar`)
+/// by the Layerfile, without building anything.
+///
+/// The reason is logged if the import is not allowed
+pub fn check_import(args: &Options, layer: &str, use_path: &str) -> cu::Result<bool> {
+    let manifest_path = Path::new(&args.manifest_path);
+    let layerfile = cu::check!(args.read_layerfile(), "failed to read Layerfile")?;
+    let manifest_info = cu::check!(
+        cargo_toml::prepare(
+            manifest_path,
+            args.workspace_root().as_deref(),
+            layerfile.crate_.entry.as_deref()
+        ),
+        "failed to prepare Cargo.toml"
+    )?;
+    let dep_graph = cu::check!(
+        DepGraph::build(&layerfile.layer),
+        "failed to build dependency graph from layers"
+    )?;
+    let entryfile_path = args.manifest_dir().join(&manifest_info.lib_entrypoint);
+    let entryfile_base_path = cu::check!(
+        entryfile_path.parent(),
+        "failed to determine base path for entry file"
+    )?;
+    let entryfile = cu::check!(
+        EntryFile::resolve(
+            &manifest_info.lib_entrypoint_content,
+            entryfile_base_path,
+            None,
+            &manifest_info.test_edition
+        ),
+        "failed to resolve modules in library entry file"
+    )?;
+
+    if !layerfile.layer.contains_key(layer) {
+        cu::bail!("layer `{layer}` is not declared in the Layerfile");
+    }
+
+    let segments = use_path
+        .split("::")
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    let segments = match segments.split_first() {
+        Some((&"crate", rest)) => rest,
+        _ => &segments[..],
+    };
+    let Some((module, rest)) = segments.split_first() else {
+        cu::bail!("invalid path to import: `{use_path}`");
+    };
+
+    if !entryfile.all_modules().contains(*module) {
+        cu::info!("`{module}` is not a module in the crate, so it's not restricted");
+        return Ok(true);
+    }
+    if layerfile.crate_.exclude.iter().any(|x| x == module) {
+        if layerfile
+            .layer
+            .get(layer)
+            .is_some_and(|x| x.unexclude.iter().any(|x| x == module))
+        {
+            cu::info!("module `{module}` is excluded, but unexcluded by layer `{layer}`");
+            return Ok(true);
+        }
+        cu::error!("module `{module}` is excluded");
+        return Ok(false);
+    }
+    let test_modules = layerfile.get_test_modules(layer)?;
+    if test_modules.iter().any(|x| x == module) {
+        cu::info!("module `{module}` is checked together with layer `{layer}`");
+        return Ok(true);
+    }
+    if layerfile.layer[layer]
+        .also_include
+        .iter()
+        .any(|x| x == module)
+    {
+        cu::info!("module `{module}` is included by layer `{layer}`");
+        return Ok(true);
+    }
+    if !layerfile.layer.contains_key(*module) {
+        cu::error!("module `{module}` is not in any layer, so it's not available to layers");
+        cu::hint!("declare [layer.{module}] and depend on it, or list it in also-include");
+        return Ok(false);
+    }
+    let is_dependency = test_modules
+        .iter()
+        .filter_map(|m| dep_graph.deps.get(m))
+        .any(|deps| deps.iter().any(|x| x == module));
+    if !is_dependency {
+        cu::error!("layer `{layer}` does not depend on `{module}`");
+        return Ok(false);
+    }
+    if let Some(denied) = layerfile.layer[layer].deny.get(*module) {
+        let is_denied = denied.iter().any(|path| {
+            layerfile::split_allowed_path(path)
+                .is_some_and(|denied| rest.len() >= denied.len() && rest[..denied.len()] == denied)
+        });
+        if is_denied {
+            cu::error!(
+                "`{}` is in the denied paths of `{module}` for layer `{layer}`",
+                rest.join("::")
+            );
+            return Ok(false);
+        }
+    }
+    let Some(allowed) = layerfile.layer[layer].allow.get(*module) else {
+        cu::info!("layer `{layer}` depends on `{module}`");
+        return Ok(true);
+    };
+    // importing an item in an allowed path is also allowed
+    let is_allowed = allowed.iter().any(|path| {
+        layerfile::split_allowed_path(path)
+            .is_some_and(|allowed| rest.len() >= allowed.len() && rest[..allowed.len()] == allowed)
+    });
+    if !is_allowed {
+        cu::error!(
+            "`{}` is not in the allowed paths of `{module}` for layer `{layer}`",
+            rest.join("::")
+        );
+        return Ok(false);
+    }
+    cu::info!(
+        "`{}` is in the allowed paths of `{module}` for layer `{layer}`",
+        rest.join("::")
+    );
+    Ok(true)
+}
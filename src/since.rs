@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use cu::pre::*;
+
+use crate::layerfile::{DepGraph, LayerFile};
+use crate::syntax::{self, EntryFile};
+
+/// Files that affect all layers when changed
+const GLOBAL_FILES: &[&str] = &["Cargo.toml", "Cargo.lock", "build.rs"];
+
+/// Find the layers that need to be checked because of changes since the git ref.
+///
+/// These are the layers with changed source files, plus the layers that
+/// depend on them. Returns `None` if all layers should be checked, which is also
+/// the case if the changes cannot be determined
+pub fn affected_layers(
+    since: &str,
+    manifest_dir: &Path,
+    layerfile: &LayerFile,
+    dep_graph: &DepGraph,
+    entryfile: &EntryFile,
+) -> Option<BTreeSet<String>> {
+    let changed_files = match changed_files(since, manifest_dir) {
+        Ok(x) => x,
+        Err(e) => {
+            cu::warn!("failed to get changed files since '{since}', checking all layers: {e}");
+            return None;
+        }
+    };
+    cu::debug!("changed files since '{since}': {changed_files:#?}");
+
+    let changed_modules =
+        changed_modules(&changed_files, layerfile, &entryfile.top_module_to_paths)?;
+    cu::debug!("changed modules: {changed_modules:?}");
+
+    // layers that depend on the changed modules need to be checked again
+    let mut affected_modules = changed_modules.clone();
+    for module in &changed_modules {
+        affected_modules.extend(dep_graph.dependents_of(module));
+    }
+    cu::debug!("affected modules: {affected_modules:?}");
+
+    // the layer also needs to be checked if any module in its test library is affected
+    let mut layers = BTreeSet::new();
+    for layer in &dep_graph.top_down_order {
+        let Ok(test_modules) = layerfile.get_test_modules(layer) else {
+            return None;
+        };
+        let also_include = &layerfile.layer[layer].also_include;
+        let unexclude = &layerfile.layer[layer].unexclude;
+        // public layers might be used by the layer without being a dependency
+        let public = layerfile
+            .layer
+            .iter()
+            .filter(|(_, x)| x.public)
+            .map(|(name, _)| name);
+        if test_modules
+            .iter()
+            .chain(also_include)
+            .chain(unexclude)
+            .chain(public)
+            .any(|m| affected_modules.contains(m))
+        {
+            layers.insert(layer.clone());
+        }
+    }
+    Some(layers)
+}
+
+/// Find the top-level modules of the changed files. Returns `None` if all layers
+/// should be checked, if a global file changed, or a file that is not in any layer
+fn changed_modules(
+    changed_files: &[PathBuf],
+    layerfile: &LayerFile,
+    top_module_to_paths: &BTreeMap<String, String>,
+) -> Option<BTreeSet<String>> {
+    let mut changed_modules = BTreeSet::new();
+    for file in changed_files {
+        let Some(file_name) = file.file_name().and_then(|x| x.to_str()) else {
+            continue;
+        };
+        if GLOBAL_FILES.contains(&file_name) || file_name.starts_with("Layerfile") {
+            cu::info!("'{}' changed, checking all layers", file.display());
+            return None;
+        }
+        if file.extension().is_none_or(|x| x != "rs") {
+            continue;
+        }
+        let module = syntax::find_module(file, top_module_to_paths);
+        match module {
+            Some(module)
+                if layerfile.layer.contains_key(module)
+                    || layerfile.crate_.exclude.iter().any(|x| x == module) =>
+            {
+                cu::trace!("'{}' is in module `{module}`", file.display());
+                changed_modules.insert(module.to_string());
+            }
+            _ => {
+                // the entry file, or modules that are not layers,
+                // which are not tracked by the dependency graph
+                cu::info!(
+                    "'{}' changed and is not in any layer, checking all layers",
+                    file.display()
+                );
+                return None;
+            }
+        }
+    }
+    Some(changed_modules)
+}
+
+/// Get the changed files in the manifest directory since the git ref, as absolute paths
+fn changed_files(since: &str, manifest_dir: &Path) -> cu::Result<Vec<PathBuf>> {
+    let manifest_dir = manifest_dir.normalize()?;
+    let (child, output) = cu::which("git")?
+        .command()
+        .args(["diff", "--name-only", "--relative", since])
+        .current_dir(&manifest_dir)
+        .stdin_null()
+        .stdout(cu::pio::string())
+        .stderr_null()
+        .spawn()?;
+    child.wait_nz()?;
+    let output = output.join()??;
+    let files = output
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| manifest_dir.join(line))
+        .collect();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module_paths() -> BTreeMap<String, String> {
+        [
+            ("app", "/c/src/app.rs"),
+            ("db", "/c/src/db/mod.rs"),
+            ("util", "/c/src/util.rs"),
+            ("bench", "/c/src/bench.rs"),
+        ]
+        .into_iter()
+        .map(|(m, p)| (m.to_string(), p.to_string()))
+        .collect()
+    }
+
+    fn layerfile() -> LayerFile {
+        toml::parse(
+            r#"
+            [crate]
+            exclude = ["bench"]
+            [layer.app]
+            depends-on = ["db"]
+            [layer.db]
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn changed(files: &[&str]) -> Option<BTreeSet<String>> {
+        let files = files.iter().map(PathBuf::from).collect::<Vec<_>>();
+        changed_modules(&files, &layerfile(), &module_paths())
+    }
+
+    #[test]
+    fn test_changed_modules_in_layers() {
+        let expected = ["app", "db"].map(String::from).into_iter().collect();
+        assert_eq!(
+            changed(&["/c/src/app.rs", "/c/src/db/query.rs", "/c/README.md"]),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn test_changed_excluded_module() {
+        let expected = ["bench"].map(String::from).into_iter().collect();
+        assert_eq!(changed(&["/c/src/bench.rs"]), Some(expected));
+    }
+
+    #[test]
+    fn test_changed_module_not_in_any_layer_checks_all() {
+        assert_eq!(changed(&["/c/src/app.rs", "/c/src/util.rs"]), None);
+    }
+
+    #[test]
+    fn test_changed_entry_file_checks_all() {
+        assert_eq!(changed(&["/c/src/lib.rs"]), None);
+        assert_eq!(changed(&["/c/Cargo.toml"]), None);
+    }
+}
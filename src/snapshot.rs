@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use cu::pre::*;
+
+use crate::Options;
+use crate::layerfile::DepGraph;
+use crate::util;
+
+/// The resolved dependency graph in a canonical form, written by the `snapshot` subcommand
+#[derive(Serialize)]
+struct GraphSnapshot<'a> {
+    /// Each layer and the layers it depends on, sorted by name
+    layers: BTreeMap<&'a str, Vec<&'a str>>,
+    top_down_order: &'a [String],
+}
+
+/// Write the snapshot of the dependency graph declared in the Layerfile.
+/// Returns the path of the snapshot
+pub fn write_snapshot(args: &Options) -> cu::Result<PathBuf> {
+    let layerfile = cu::check!(args.read_layerfile(), "failed to read Layerfile")?;
+    let dep_graph = cu::check!(
+        DepGraph::build(&layerfile.layer),
+        "failed to build dependency graph from Layerfile"
+    )?;
+    let path = args.snapshot_path();
+    cu::check!(
+        cu::fs::write(&path, render(&dep_graph)?),
+        "failed to write snapshot to '{}'",
+        path.display()
+    )?;
+    Ok(path)
+}
+
+/// Check the dependency graph is the same as the snapshot, with `--check-snapshot`
+pub fn check_snapshot(path: &Path, dep_graph: &DepGraph) -> cu::Result<()> {
+    if !path.exists() {
+        cu::error!("snapshot '{}' does not exist", path.display());
+        cu::hint!("run `layered-crate snapshot` to create it");
+        cu::bail!("snapshot not found");
+    }
+    let snapshot = cu::fs::read_string(path)?;
+    let diff = util::unified_diff(&snapshot, &render(dep_graph)?);
+    if diff.is_empty() {
+        cu::debug!("dependency graph matches the snapshot");
+        return Ok(());
+    }
+    cu::error!(
+        "dependency graph is different from the snapshot '{}':\n{diff}",
+        path.display()
+    );
+    cu::hint!("if the change is intended, run `layered-crate snapshot` to update the snapshot");
+    cu::bail!("dependency graph is different from the snapshot");
+}
+
+fn render(dep_graph: &DepGraph) -> cu::Result<String> {
+    let layers = dep_graph
+        .deps
+        .iter()
+        .map(|(name, deps)| {
+            let mut deps = deps.iter().map(String::as_str).collect::<Vec<_>>();
+            deps.sort_unstable();
+            (name.as_str(), deps)
+        })
+        .collect();
+    let snapshot = GraphSnapshot {
+        layers,
+        top_down_order: &dep_graph.top_down_order,
+    };
+    Ok(format!("{}\n", json::stringify_pretty(&snapshot)?))
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+
+use cu::pre::*;
+
+use crate::Options;
+use crate::layerfile::DepGraph;
+
+/// Metrics of the dependency graph, printed with the `stats` subcommand
+#[derive(Debug, Serialize)]
+pub struct GraphStats {
+    /// Number of layers
+    pub layers: usize,
+    /// Number of `depends-on` edges between layers
+    pub edges: usize,
+    /// The longest dependency chain, from the top layer to the bottom layer
+    pub longest_chain: Vec<String>,
+    /// The layer(s) with the most dependencies
+    pub max_fan_out: MaxCount,
+    /// The layer(s) with the most dependents
+    pub max_fan_in: MaxCount,
+}
+
+/// Layers that have the highest count of something
+#[derive(Debug, Default, Serialize)]
+pub struct MaxCount {
+    pub layers: Vec<String>,
+    pub count: usize,
+}
+
+impl std::fmt::Display for GraphStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "layers: {}", self.layers)?;
+        writeln!(f, "edges: {}", self.edges)?;
+        writeln!(
+            f,
+            "depth: {} ({})",
+            self.longest_chain.len(),
+            self.longest_chain.join(" -> ")
+        )?;
+        let fan_out = &self.max_fan_out;
+        writeln!(
+            f,
+            "max fan-out: {} ({})",
+            fan_out.count,
+            fan_out.layers.join(", ")
+        )?;
+        let fan_in = &self.max_fan_in;
+        write!(
+            f,
+            "max fan-in: {} ({})",
+            fan_in.count,
+            fan_in.layers.join(", ")
+        )
+    }
+}
+
+/// Compute the metrics of the dependency graph declared in the Layerfile
+pub fn graph_stats(args: &Options) -> cu::Result<GraphStats> {
+    let layerfile = cu::check!(args.read_layerfile(), "failed to read Layerfile")?;
+    let dep_graph = cu::check!(
+        DepGraph::build(&layerfile.layer),
+        "failed to build dependency graph from Layerfile"
+    )?;
+
+    // longest chain starting from each layer, computed bottom-up
+    // so the chains of the dependencies are known
+    let mut chains = BTreeMap::<&str, Vec<String>>::new();
+    for name in dep_graph.top_down_order.iter().rev() {
+        let longest = dep_graph.deps[name]
+            .iter()
+            .filter_map(|dep| chains.get(dep.as_str()))
+            .max_by_key(|chain| chain.len());
+        let mut chain = vec![name.clone()];
+        if let Some(longest) = longest {
+            chain.extend(longest.iter().cloned());
+        }
+        chains.insert(name, chain);
+    }
+    // the first one in top-down order wins a tie
+    let longest_chain = dep_graph
+        .top_down_order
+        .iter()
+        .filter_map(|name| chains.remove(name.as_str()))
+        .rev()
+        .max_by_key(|chain| chain.len())
+        .unwrap_or_default();
+
+    let fan_out = dep_graph.deps.iter().map(|(name, deps)| (name, deps.len()));
+    let fan_in = dep_graph
+        .dependents
+        .iter()
+        .filter(|(name, _)| dep_graph.deps.contains_key(*name))
+        .map(|(name, dependents)| (name, dependents.len()));
+
+    Ok(GraphStats {
+        layers: dep_graph.deps.len(),
+        edges: dep_graph.deps.values().map(|deps| deps.len()).sum(),
+        longest_chain,
+        max_fan_out: max_by_count(fan_out),
+        max_fan_in: max_by_count(fan_in),
+    })
+}
+
+/// Find the layers with the highest count
+fn max_by_count<'a>(counts: impl Iterator<Item = (&'a String, usize)>) -> MaxCount {
+    let mut max = MaxCount::default();
+    for (name, count) in counts {
+        if count > max.count {
+            max = MaxCount {
+                layers: vec![],
+                count,
+            };
+        }
+        if count == max.count && count > 0 {
+            max.layers.push(name.clone());
+        }
+    }
+    max
+}
@@ -4,7 +4,7 @@ use std::path::Path;
 use cu::pre::*;
 use pm::pre::*;
 
-use crate::util;
+use crate::{layerfile, util};
 
 pub struct EntryFile {
     /// The file's syntax tree with modifications
@@ -12,24 +12,47 @@ pub struct EntryFile {
 
     /// Map from top-level module names to their absolute paths
     pub top_module_to_paths: BTreeMap<String, String>,
+
+    /// Command to format the produced source code, instead of `rustfmt`
+    pub format_cmd: Option<String>,
+    /// Edition of the test package, which the produced source code is formatted
+    /// and generated for
+    pub edition: String,
 }
 
 impl EntryFile {
-    pub fn resolve(content: &str, base_path: &Path) -> cu::Result<Self> {
+    pub fn resolve(
+        content: &str,
+        base_path: &Path,
+        format_cmd: Option<String>,
+        edition: &str,
+    ) -> cu::Result<Self> {
         cu::debug!("parsing entry file content");
 
-        let mut syntax = cu::check!(
-            syn::parse_file(content),
-            "failed to parse entrypoint for the library - there are syntax errors."
+        let mut syntax = match syn::parse_file(content) {
+            Ok(x) => x,
+            Err(e) => {
+                cu::error!("{}", describe_syntax_error(content, &e));
+                cu::bail!("failed to parse entrypoint for the library - there are syntax errors.");
+            }
+        };
+        check_no_layers_attribute(&syntax)?;
+        check_extern_crate_names(&syntax)?;
+        // module paths are resolved from here without resolving symlinks
+        let base_path = base_path.normalize()?;
+        syntax = cu::check!(
+            resolve_include_paths(&syntax, &base_path),
+            "failed to resolve paths in include macros in the entrypoint file"
         )?;
+
         let mut resolve_map = BTreeMap::new();
         cu::check!(
             resolve_items(
                 "crate",
                 &mut syntax.items,
-                base_path,
+                &base_path,
                 true,
-                &mut resolve_map,
+                Some(&mut resolve_map),
             ),
             "failed to resolve items in the entrypoint file"
         )?;
@@ -38,9 +61,42 @@ impl EntryFile {
         Ok(Self {
             syntax,
             top_module_to_paths: resolve_map,
+            format_cmd,
+            edition: edition.to_string(),
         })
     }
 
+    /// Find the `pub use` re-exports of the modules in the entry file.
+    ///
+    /// Returns the module and the path that is re-exported
+    pub fn find_reexports(&self, modules: &[String]) -> Vec<(String, String)> {
+        let mut output = Vec::new();
+        for item in &self.syntax.items {
+            let syn::Item::Use(item_use) = item else {
+                continue;
+            };
+            if !matches!(item_use.vis, syn::Visibility::Public(_))
+                || item_use.leading_colon.is_some()
+            {
+                continue;
+            }
+            let mut paths = Vec::new();
+            collect_use_paths(&item_use.tree, &mut vec![], &mut paths);
+            for path in paths {
+                let segments = match path.split_first() {
+                    Some((first, rest)) if first == "crate" || first == "self" => rest,
+                    _ => &path[..],
+                };
+                if let Some(module) = segments.first()
+                    && modules.contains(module)
+                {
+                    output.push((module.clone(), path.join("::")));
+                }
+            }
+        }
+        output
+    }
+
     /// Get all top level module names in the entry file
     pub fn all_modules(&self) -> BTreeSet<String> {
         let mut modules = BTreeSet::new();
@@ -54,13 +110,75 @@ impl EntryFile {
 
     /// Produce the library source code as a string.
     pub fn produce_lib(&self) -> String {
-        util::run_rustfmt(self.syntax.to_token_stream().to_string())
+        util::run_rustfmt(
+            self.syntax.to_token_stream().to_string(),
+            self.format_cmd.as_deref(),
+            &self.edition,
+        )
+    }
+
+    /// Produce the module declaration of a test module in the test library.
+    ///
+    /// Inline modules are copied as-is, since their content is in the entry file.
+    /// If `strip_tests` is true, `#[cfg(test)]` items in the module are removed,
+    /// which requires the module file to be inlined as well
+    fn produce_test_module(
+        &self,
+        test_module: &str,
+        strip_tests: bool,
+    ) -> cu::Result<TokenStream2> {
+        let ident = syn::Ident::new(test_module, Span2::call_site());
+        if let Some(path) = self.top_module_to_paths.get(test_module) {
+            if !strip_tests {
+                return Ok(pm::quote! {
+                    #[path = #path]
+                    pub mod #ident;
+                });
+            }
+            let mut file = cu::check!(
+                parse_module_file(test_module, Path::new(path)),
+                "failed to inline module `{test_module}`"
+            )?;
+            strip_cfg_test_items(&mut file.items);
+            let inner_attrs = file.attrs;
+            let items = file.items;
+            return Ok(pm::quote! {
+                pub mod #ident {
+                    #(#inner_attrs)*
+                    #(#items)*
+                }
+            });
+        }
+        let inline_module = self.syntax.items.iter().find_map(|item| match item {
+            syn::Item::Mod(item_mod)
+                if item_mod.ident == test_module && item_mod.content.is_some() =>
+            {
+                Some(item_mod)
+            }
+            _ => None,
+        });
+        let inline_module = cu::check!(
+            inline_module,
+            "test module `{test_module}` not found in entry file"
+        )?;
+        if !strip_tests {
+            return Ok(inline_module.to_token_stream());
+        }
+        let mut inline_module = inline_module.clone();
+        if let Some((_, items)) = &mut inline_module.content {
+            strip_cfg_test_items(items);
+        }
+        Ok(inline_module.to_token_stream())
     }
 
     pub fn produce_test_lib(
         &self,
         test_modules: &[String],
         dependencies: &BTreeSet<String>,
+        included_modules: &BTreeSet<String>,
+        layer: &layerfile::Layer,
+        glob_imports: &BTreeSet<String>,
+        strip_tests: &BTreeSet<String>,
     ) -> cu::Result<String> {
         cu::debug!(
             "producing test library with test modules: {test_modules:?}, dependencies: {dependencies:?}"
@@ -69,7 +187,10 @@ impl EntryFile {
         // keep the original crate attributes
         let file_attrs = &self.syntax.attrs;
 
-        // keep "extern crate"s
+        // keep "extern crate"s, including attributes like #[macro_use],
+        // so macros from them are still in scope in the test modules.
+        // renames like `extern crate alloc as mem;` are kept as well,
+        // since the test modules refer to the crate by the new name
         let mut extern_crates = Vec::new();
         for item in &self.syntax.items {
             if let syn::Item::ExternCrate(item_extern) = item {
@@ -77,39 +198,440 @@ impl EntryFile {
             }
         }
 
-        let test_module_paths = test_modules
+        let test_module_items = test_modules
             .iter()
             .map(|test_module| {
-                cu::check!(
-                    self.top_module_to_paths.get(test_module),
-                    "test module `{}` not found in entry file",
-                    test_module
-                )
+                self.produce_test_module(test_module, strip_tests.contains(test_module))
             })
-            .collect::<Result<Vec<_>, _>>()?;
+            .collect::<cu::Result<Vec<_>>>()?;
 
-        let test_module_idents = test_modules
-            .iter()
-            .map(|test_module| syn::Ident::new(test_module, Span2::call_site()))
-            .collect::<Vec<_>>();
+        let mut dep_idents = Vec::new();
+        let mut restricted_deps = Vec::new();
+        for dep in dependencies {
+            let (paths, is_allow) = match (layer.allow.get(dep), layer.deny.get(dep)) {
+                (Some(paths), _) => (paths, true),
+                (None, Some(paths)) => (paths, false),
+                (None, None) => {
+                    dep_idents.push(syn::Ident::new(dep, Span2::call_site()));
+                    continue;
+                }
+            };
+            let mut tree = PathTree::default();
+            for path in paths {
+                let segments = cu::check!(
+                    layerfile::split_allowed_path(path),
+                    "invalid path `{path}` for `{dep}`"
+                )?;
+                tree.insert(&segments);
+            }
+            let dep_ident = syn::Ident::new(dep, Span2::call_site());
+            let base = pm::quote! { ::__layer_test::#dep_ident };
+            // only re-export the allowed items, or shadow the denied items,
+            // so using anything not allowed from the dependency fails to resolve
+            let content = if is_allow {
+                tree.to_allowed_tokens(&base)
+            } else {
+                tree.to_denied_tokens(&base)
+            };
+            restricted_deps.push(pm::quote! {
+                pub mod #dep_ident { #content }
+            });
+        }
 
-        let dep_idents = dependencies
+        let included_idents = included_modules
             .iter()
-            .map(|dep| syn::Ident::new(dep, Span2::call_site()))
-            .collect::<Vec<_>>();
+            .map(|m| syn::Ident::new(m, Span2::call_site()));
+        let glob_idents = glob_imports
+            .iter()
+            .map(|m| syn::Ident::new(m, Span2::call_site()));
+
+        // paths in `use` are relative to the crate root in 2015 edition,
+        // where the extern crate needs to be declared
+        let extern_layer_test = (self.edition == "2015").then(|| {
+            pm::quote! { extern crate __layer_test; }
+        });
 
         let test_file = pm::quote! {
             #(#file_attrs)*
-            #(#extern_crates)*
-
+            #extern_layer_test
+            // the macros might only be used by modules not in this test library
             #(
-                #[path = #test_module_paths]
-                pub mod #test_module_idents;
+                #[allow(unused_imports)]
+                #extern_crates
             )*
 
+            #(#test_module_items)*
+
             #( use ::__layer_test::#dep_idents;)*
+            #(#restricted_deps)*
+            // only the items of restricted dependencies that are allowed are glob imported
+            #(
+                #[allow(unused_imports)]
+                pub use #glob_idents::*;
+            )*
+            // modules in also-include or unexclude of the layer
+            #(
+                #[allow(unused_imports)]
+                use ::__layer_test::#included_idents;
+            )*
         };
-        Ok(util::run_rustfmt(test_file.to_string()))
+        Ok(util::run_rustfmt(
+            test_file.to_string(),
+            self.format_cmd.as_deref(),
+            &self.edition,
+        ))
+    }
+}
+
+/// Find the top-level module the file (absolute path) belongs to
+pub fn find_module<'a>(
+    file: &Path,
+    top_module_to_paths: &'a BTreeMap<String, String>,
+) -> Option<&'a str> {
+    for (module, path) in top_module_to_paths {
+        let path = Path::new(path);
+        if file == path {
+            return Some(module);
+        }
+        // sub-modules are in the directory of the module, which is
+        // `foo/` for both `foo.rs` and `foo/mod.rs`
+        let module_dir = if path.file_name().is_some_and(|x| x == "mod.rs") {
+            path.parent().map(Path::to_path_buf)
+        } else {
+            Some(path.with_extension(""))
+        };
+        if module_dir.is_some_and(|dir| file.starts_with(dir)) {
+            return Some(module);
+        }
+    }
+    None
+}
+
+/// Parse a module file, resolving the sub-modules declared in it to absolute paths,
+/// so the content can be inlined in another file
+fn parse_module_file(module: &str, path: &Path) -> cu::Result<syn::File> {
+    cu::debug!("parsing module file: {}", path.display());
+    let content = cu::fs::read_string(path)?;
+    let mut file = match syn::parse_file(&content) {
+        Ok(x) => x,
+        Err(e) => {
+            cu::error!("{}", describe_syntax_error(&content, &e));
+            cu::bail!("failed to parse module file '{}'", path.display());
+        }
+    };
+    let file_dir = cu::check!(
+        path.parent(),
+        "failed to get directory of module file '{}'",
+        path.display()
+    )?
+    .to_path_buf();
+    file = cu::check!(
+        resolve_include_paths(&file, &file_dir),
+        "failed to resolve paths in include macros in module file '{}'",
+        path.display()
+    )?;
+    // #[path] is relative to the directory of the file
+    for item in &mut file.items {
+        if let syn::Item::Mod(item) = item
+            && let Some(path_attr) = item
+                .attrs
+                .iter_mut()
+                .find(|attr| attr.path().is_ident("path"))
+            && let syn::Meta::NameValue(meta) = &mut path_attr.meta
+            && let syn::Expr::Lit(expr) = &mut meta.value
+            && let syn::Lit::Str(lit) = &mut expr.lit
+        {
+            let module_path = cu::check!(
+                util::resolve_module_path(lit.value(), &file_dir),
+                "failed to resolve path for module `{}` in {module}",
+                item.ident
+            )?;
+            *lit = syn::LitStr::new(&module_path, lit.span());
+        }
+        if let syn::Item::Mod(item) = item {
+            cu::check!(
+                resolve_cfg_attr_paths(&mut item.attrs, Some(&file_dir)),
+                "failed to resolve cfg_attr path for module `{}` in {module}",
+                item.ident
+            )?;
+        }
+    }
+    // other sub-modules are in the directory of the module,
+    // which is `foo/` for both `foo.rs` and `foo/mod.rs`
+    let module_dir = if path.file_name().is_some_and(|x| x == "mod.rs") {
+        file_dir
+    } else {
+        path.with_extension("")
+    };
+    cu::check!(
+        resolve_items(module, &mut file.items, &module_dir, false, None),
+        "failed to resolve items in module file '{}'",
+        path.display()
+    )?;
+    Ok(file)
+}
+
+/// Rewrite the relative paths in `include!`, `include_str!` and `include_bytes!`
+/// (including `#[doc = include_str!(...)]`) to absolute paths from the directory of the file,
+/// since the content of the file is moved or inlined in the generated package
+fn resolve_include_paths(file: &syn::File, file_dir: &Path) -> cu::Result<syn::File> {
+    let tokens = resolve_include_paths_in_tokens(file.to_token_stream(), file_dir);
+    Ok(syn::parse2(tokens)?)
+}
+
+fn resolve_include_paths_in_tokens(tokens: TokenStream2, file_dir: &Path) -> TokenStream2 {
+    let mut trees = tokens.into_iter().collect::<Vec<_>>();
+    for i in 0..trees.len() {
+        let pm::TokenTree2::Group(group) = &trees[i] else {
+            continue;
+        };
+        let is_include = i >= 2
+            && matches!(&trees[i - 2], pm::TokenTree2::Ident(x)
+                if x == "include" || x == "include_str" || x == "include_bytes")
+            && matches!(&trees[i - 1], pm::TokenTree2::Punct(x) if x.as_char() == '!');
+        let stream = if is_include {
+            resolve_include_path(group.stream(), file_dir)
+        } else {
+            resolve_include_paths_in_tokens(group.stream(), file_dir)
+        };
+        let mut resolved = pm::Group2::new(group.delimiter(), stream);
+        resolved.set_span(group.span());
+        trees[i] = pm::TokenTree2::Group(resolved);
+    }
+    trees.into_iter().collect()
+}
+
+/// Resolve the path in the argument of an include macro, if it's a relative path literal
+fn resolve_include_path(tokens: TokenStream2, file_dir: &Path) -> TokenStream2 {
+    let Ok(lit) = syn::parse2::<syn::LitStr>(tokens.clone()) else {
+        return tokens;
+    };
+    let path = lit.value();
+    if Path::new(&path).is_absolute() {
+        return tokens;
+    }
+    let Ok(resolved) = file_dir.join(&path).into_utf8() else {
+        return tokens;
+    };
+    let resolved = util::to_forward_slashes(&resolved);
+    cu::trace!("resolved include path `{path}` to '{resolved}'");
+    syn::LitStr::new(&resolved, lit.span()).into_token_stream()
+}
+
+/// Describe where the syntax errors are, with the line of the source and
+/// a marker under the column of each error
+fn describe_syntax_error(content: &str, error: &syn::Error) -> String {
+    let mut output = String::new();
+    for (i, error) in error.clone().into_iter().enumerate() {
+        if i > 0 {
+            output.push('\n');
+        }
+        let start = error.span().start();
+        let line_number = start.line.to_string();
+        let line = content
+            .lines()
+            .nth(start.line.saturating_sub(1))
+            .unwrap_or_default();
+        let marker = line
+            .chars()
+            .take(start.column)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let padding = " ".repeat(line_number.len());
+        output.push_str(&format!(
+            "{error} at line {}, column {}\n{padding} |\n{line_number} | {line}\n{padding} | {marker}^",
+            start.line,
+            start.column + 1
+        ));
+    }
+    output
+}
+
+/// Collect the paths imported by a `use` tree, for example
+/// `["crate", "foo", "Bar"]` for `use crate::foo::Bar;`
+fn collect_use_paths(tree: &syn::UseTree, prefix: &mut Vec<String>, output: &mut Vec<Vec<String>>) {
+    match tree {
+        syn::UseTree::Path(x) => {
+            prefix.push(x.ident.to_string());
+            collect_use_paths(&x.tree, prefix, output);
+            prefix.pop();
+        }
+        syn::UseTree::Name(x) => {
+            let mut path = prefix.clone();
+            path.push(x.ident.to_string());
+            output.push(path);
+        }
+        syn::UseTree::Rename(x) => {
+            let mut path = prefix.clone();
+            path.push(x.ident.to_string());
+            output.push(path);
+        }
+        syn::UseTree::Glob(_) => {
+            let mut path = prefix.clone();
+            path.push("*".to_string());
+            output.push(path);
+        }
+        syn::UseTree::Group(x) => {
+            for tree in &x.items {
+                collect_use_paths(tree, prefix, output);
+            }
+        }
+    }
+}
+
+/// Remove items marked with `#[cfg(test)]`
+fn strip_cfg_test_items(items: &mut Vec<syn::Item>) {
+    items.retain(|item| {
+        let attrs: &[syn::Attribute] = match item {
+            syn::Item::Const(x) => &x.attrs,
+            syn::Item::Enum(x) => &x.attrs,
+            syn::Item::ExternCrate(x) => &x.attrs,
+            syn::Item::Fn(x) => &x.attrs,
+            syn::Item::ForeignMod(x) => &x.attrs,
+            syn::Item::Impl(x) => &x.attrs,
+            syn::Item::Macro(x) => &x.attrs,
+            syn::Item::Mod(x) => &x.attrs,
+            syn::Item::Static(x) => &x.attrs,
+            syn::Item::Struct(x) => &x.attrs,
+            syn::Item::Trait(x) => &x.attrs,
+            syn::Item::TraitAlias(x) => &x.attrs,
+            syn::Item::Type(x) => &x.attrs,
+            syn::Item::Union(x) => &x.attrs,
+            syn::Item::Use(x) => &x.attrs,
+            _ => &[],
+        };
+        let is_cfg_test = attrs.iter().any(|attr| {
+            attr.path().is_ident("cfg")
+                && attr
+                    .parse_args::<syn::Ident>()
+                    .is_ok_and(|ident| ident == "test")
+        });
+        !is_cfg_test
+    });
+}
+
+/// Check the names of `extern crate`s (or their renames, like `extern crate alloc as mem;`)
+/// do not conflict with `__layer_test`, since they are kept in the test library
+/// to keep the names available to the test modules
+fn check_extern_crate_names(syntax: &syn::File) -> cu::Result<()> {
+    for item in &syntax.items {
+        let syn::Item::ExternCrate(item_extern) = item else {
+            continue;
+        };
+        let name = match &item_extern.rename {
+            Some((_, rename)) => rename,
+            None => &item_extern.ident,
+        };
+        cu::trace!("found extern crate `{}` as `{name}`", item_extern.ident);
+        if name == "__layer_test" {
+            cu::bail!(
+                "extern crate `{}` is named `__layer_test`, which is reserved for the crate in the test package",
+                item_extern.ident
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Check the entry file is not using the `#[layers]` attribute from
+/// the proc-macro version of this crate (0.2 and before)
+fn check_no_layers_attribute(syntax: &syn::File) -> cu::Result<()> {
+    let is_layers_attr = |attr: &syn::Attribute| {
+        let path = attr.path();
+        path.is_ident("layers")
+            || (path.segments.len() == 2
+                && path.segments[0].ident == "layered_crate"
+                && path.segments[1].ident == "layers")
+    };
+    let has_layers_attr = syntax.attrs.iter().any(is_layers_attr)
+        || syntax.items.iter().any(|item| match item {
+            syn::Item::Mod(item_mod) => item_mod.attrs.iter().any(is_layers_attr),
+            _ => false,
+        });
+    if has_layers_attr {
+        cu::error!("found `#[layers]` attribute in the entry file");
+        cu::hint!(
+            "the `#[layered_crate::layers]` attribute is from the proc-macro version of layered-crate (0.2), which is replaced by this CLI tool."
+        );
+        cu::hint!(
+            "to migrate, remove the attribute and move the modules out of the `mod src {{ ... }}` wrapper, then declare the layers in Layerfile.toml"
+        );
+        cu::bail!("the `#[layers]` attribute is not supported");
+    }
+    Ok(())
+}
+
+/// Tree of allowed or denied paths in a dependency
+#[derive(Default)]
+struct PathTree<'a> {
+    /// Items allowed or denied directly in this module
+    items: BTreeSet<&'a str>,
+    /// Sub-modules where only part of the items are allowed or denied
+    modules: BTreeMap<&'a str, PathTree<'a>>,
+}
+
+impl<'a> PathTree<'a> {
+    fn insert(&mut self, segments: &[&'a str]) {
+        match segments {
+            [] => {}
+            [item] => {
+                self.items.insert(item);
+            }
+            [module, rest @ ..] => self.modules.entry(module).or_default().insert(rest),
+        }
+    }
+
+    /// Produce the re-exports of the allowed items, with `base` being the path to the current module
+    fn to_allowed_tokens(&self, base: &TokenStream2) -> TokenStream2 {
+        let items = self
+            .items
+            .iter()
+            .map(|item| syn::Ident::new(item, Span2::call_site()));
+        let modules = self
+            .modules
+            .iter()
+            // allowing the whole module takes precedence over allowing part of it
+            .filter(|(module, _)| !self.items.contains(*module))
+            .map(|(module, tree)| {
+                let module = syn::Ident::new(module, Span2::call_site());
+                let content = tree.to_allowed_tokens(&pm::quote! { #base::#module });
+                pm::quote! { pub mod #module { #content } }
+            });
+        pm::quote! {
+            #( pub use #base::#items; )*
+            #(#modules)*
+        }
+    }
+
+    /// Produce the glob re-export of the current module, with the denied items
+    /// shadowed in both the type and value namespaces, with `base` being the path to the current module
+    fn to_denied_tokens(&self, base: &TokenStream2) -> TokenStream2 {
+        let items = self
+            .items
+            .iter()
+            .map(|item| syn::Ident::new(item, Span2::call_site()))
+            .collect::<Vec<_>>();
+        let modules = self
+            .modules
+            .iter()
+            // denying the whole module takes precedence over denying part of it
+            .filter(|(module, _)| !self.items.contains(*module))
+            .map(|(module, tree)| {
+                let module = syn::Ident::new(module, Span2::call_site());
+                let content = tree.to_denied_tokens(&pm::quote! { #base::#module });
+                pm::quote! { pub mod #module { #content } }
+            });
+        pm::quote! {
+            #[allow(unused_imports)]
+            pub use #base::*;
+            #(
+                #[allow(non_snake_case)]
+                pub mod #items {}
+                #[allow(non_upper_case_globals)]
+                pub const #items: () = ();
+            )*
+            #(#modules)*
+        }
     }
 }
 
@@ -119,7 +641,8 @@ fn resolve_items(
     items: &mut Vec<syn::Item>,
     base_path: &Path,
     resolve_path_attrs: bool,
-    resolve_map: &mut BTreeMap<String, String>,
+    // only top-level modules are recorded
+    mut resolve_map: Option<&mut BTreeMap<String, String>>,
 ) -> cu::Result<()> {
     cu::debug!(
         "resolving items in {tag}, base path: {}",
@@ -134,6 +657,11 @@ fn resolve_items(
             cu::trace!("making module `{}` public", item.ident);
             item.vis = syn::parse_quote! { pub };
         }
+        let cfg_attr_paths = cu::check!(
+            resolve_cfg_attr_paths(&mut item.attrs, resolve_path_attrs.then_some(base_path)),
+            "failed to resolve cfg_attr path for module `{}` in {tag}",
+            item.ident
+        )?;
         // if the item already as a path attribute, resolve it to absolute path from base
         if let Some(path_attr) = item
             .attrs
@@ -141,20 +669,20 @@ fn resolve_items(
             .find(|attr| attr.path().is_ident("path"))
         {
             cu::trace!("found path attribute for module: {}", item.ident);
-            if resolve_path_attrs {
-                if let syn::Meta::NameValue(meta) = &mut path_attr.meta {
-                    if let syn::Expr::Lit(expr) = &mut meta.value {
-                        if let syn::Lit::Str(lit) = &mut expr.lit {
-                            let module_path = cu::check!(
-                                util::resolve_path(lit.value(), base_path),
-                                "failed to resolve path for module `{}` in {tag}",
-                                item.ident
-                            )?;
-                            resolve_map.insert(item.ident.to_string(), module_path.clone());
-                            *lit = syn::LitStr::new(&module_path, lit.span());
-                        }
-                    }
+            if resolve_path_attrs
+                && let syn::Meta::NameValue(meta) = &mut path_attr.meta
+                && let syn::Expr::Lit(expr) = &mut meta.value
+                && let syn::Lit::Str(lit) = &mut expr.lit
+            {
+                let module_path = cu::check!(
+                    util::resolve_module_path(lit.value(), base_path),
+                    "failed to resolve path for module `{}` in {tag}",
+                    item.ident
+                )?;
+                if let Some(resolve_map) = resolve_map.as_deref_mut() {
+                    resolve_map.insert(item.ident.to_string(), module_path.clone());
                 }
+                *lit = syn::LitStr::new(&module_path, lit.span());
             }
         } else {
             // otherwise, resolve the module path based on the module name
@@ -167,24 +695,56 @@ fn resolve_items(
                 );
                 let child_tag = format!("{tag}::{}", item.ident);
                 let child_path = base_path.join(item.ident.to_string());
+                // #[path] in inline modules is relative to the directory of the inline module,
+                // which is resolved since the file is moved or inlined in the generated package
                 cu::check!(
-                    resolve_items(&child_tag, child_items, &child_path, false, resolve_map),
+                    resolve_items(&child_tag, child_items, &child_path, true, None),
                     "failed to resolve items in inline module `{}` in {tag}",
                     item.ident
                 )?;
                 continue;
             }
             // add path attribute to non-inline modules
-            let path = cu::check!(
-                resolve_module(tag, &item.ident, base_path),
-                "failed to resolve module `{}` in {tag}",
+            let (path, default_path) = match resolve_module(tag, &item.ident, base_path) {
+                Ok(path) => (path.clone(), path),
+                Err(e) => match cfg_attr_paths.first() {
+                    // the module file only exists at the path in cfg_attr, the default path
+                    // is still added, so the module is not found when the condition is false,
+                    // same as the crate
+                    Some((_, path)) => {
+                        let default_path = base_path.join(format!("{}.rs", item.ident));
+                        (
+                            path.clone(),
+                            util::to_forward_slashes(&default_path.into_utf8()?),
+                        )
+                    }
+                    None => {
+                        return Err(e).context(format!(
+                            "failed to resolve module `{}` in {tag}",
+                            item.ident
+                        ));
+                    }
+                },
+            };
+            cu::trace!(
+                "adding path attribute to module `{}`: {default_path}",
                 item.ident
-            )?;
-            cu::trace!("adding path attribute to module `{}`: {}", item.ident, path);
-            item.attrs.push(syn::parse_quote! {
-                #[path = #path]
-            });
-            resolve_map.insert(item.ident.to_string(), path.clone());
+            );
+            if cfg_attr_paths.is_empty() {
+                item.attrs.push(syn::parse_quote! {
+                    #[path = #default_path]
+                });
+            } else {
+                // the default path is only used if no condition of the cfg_attr(s) is true,
+                // since rustc warns about multiple path attributes
+                let conditions = cfg_attr_paths.iter().map(|(condition, _)| condition);
+                item.attrs.push(syn::parse_quote! {
+                    #[cfg_attr(not(any(#(#conditions),*)), path = #default_path)]
+                });
+            }
+            if let Some(resolve_map) = resolve_map.as_deref_mut() {
+                resolve_map.insert(item.ident.to_string(), path.clone());
+            }
         }
     }
 
@@ -192,6 +752,56 @@ fn resolve_items(
     Ok(())
 }
 
+/// Get the conditions and paths in `#[cfg_attr(<condition>, path = "...")]` of a module.
+///
+/// If `base_path` is specified, the paths are resolved from it and replaced
+/// with the absolute paths in the attributes
+fn resolve_cfg_attr_paths(
+    attrs: &mut [syn::Attribute],
+    base_path: Option<&Path>,
+) -> cu::Result<Vec<(syn::Meta, String)>> {
+    let mut paths = Vec::new();
+    for attr in attrs {
+        if !attr.path().is_ident("cfg_attr") {
+            continue;
+        }
+        let syn::Meta::List(list) = &mut attr.meta else {
+            continue;
+        };
+        let mut metas = cu::check!(
+            list.parse_args_with(
+                syn::punctuated::Punctuated::<syn::Meta, syn::Token![,]>::parse_terminated
+            ),
+            "failed to parse cfg_attr"
+        )?;
+        let mut changed = false;
+        let Some(condition) = metas.first().cloned() else {
+            continue;
+        };
+        for meta in metas.iter_mut().skip(1) {
+            if let syn::Meta::NameValue(meta) = meta
+                && meta.path.is_ident("path")
+                && let syn::Expr::Lit(expr) = &mut meta.value
+                && let syn::Lit::Str(lit) = &mut expr.lit
+            {
+                let Some(base_path) = base_path else {
+                    paths.push((condition.clone(), lit.value()));
+                    continue;
+                };
+                let module_path = util::resolve_module_path(lit.value(), base_path)?;
+                cu::trace!("resolved cfg_attr path: {module_path}");
+                *lit = syn::LitStr::new(&module_path, lit.span());
+                paths.push((condition.clone(), module_path));
+                changed = true;
+            }
+        }
+        if changed {
+            list.tokens = metas.to_token_stream();
+        }
+    }
+    Ok(paths)
+}
+
 fn resolve_module(tag: &str, module_ident: &syn::Ident, base_path: &Path) -> cu::Result<String> {
     cu::trace!(
         "resolving module `{}` in {tag}, base path: {}",
@@ -202,16 +812,249 @@ fn resolve_module(tag: &str, module_ident: &syn::Ident, base_path: &Path) -> cu:
     let module_name = module_ident.to_string();
 
     // <base_path>/module_ident.rs
-    if let Ok(module_path) = util::resolve_path(format!("{module_name}.rs"), base_path) {
+    if let Ok(module_path) = util::resolve_module_path(format!("{module_name}.rs"), base_path) {
         cu::trace!("found module file at {module_path}");
         return Ok(module_path);
     }
 
     // <base_path>/module_ident/mod.rs
     let module_path = cu::check!(
-        util::resolve_path(format!("{module_name}/mod.rs"), base_path),
+        util::resolve_module_path(format!("{module_name}/mod.rs"), base_path),
         "failed to resolve module `{module_name}` in {tag}"
     )?;
     cu::trace!("found module file at {module_path}");
     Ok(module_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn resolve(content: &str) -> EntryFile {
+        let base_path = Path::new(env!("CARGO_MANIFEST_DIR"));
+        EntryFile::resolve(content, base_path, None, "2021").unwrap()
+    }
+
+    /// Produce the test library and parse it back, with the tests stripped from the layer
+    fn produce(entryfile: &EntryFile, test_modules: &[&str], dependencies: &[&str]) -> syn::File {
+        let test_modules = test_modules
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<_>>();
+        let dependencies = dependencies.iter().map(|m| m.to_string()).collect();
+        let strip_tests = BTreeSet::from([test_modules[0].clone()]);
+        let test_lib = entryfile
+            .produce_test_lib(
+                &test_modules,
+                &dependencies,
+                &BTreeSet::new(),
+                &toml::parse("").unwrap(),
+                &BTreeSet::new(),
+                &strip_tests,
+            )
+            .unwrap();
+        syn::parse_file(&test_lib).unwrap()
+    }
+
+    #[test]
+    fn test_inline_modules_as_layers() {
+        let entryfile = resolve(
+            r#"
+            pub mod util {
+                pub fn helper() {}
+            }
+            pub mod db {
+                pub fn query() {
+                    crate::util::helper();
+                }
+                #[cfg(test)]
+                mod tests {}
+            }
+            "#,
+        );
+        assert_eq!(
+            entryfile.all_modules(),
+            BTreeSet::from(["db".to_string(), "util".to_string()])
+        );
+        assert!(entryfile.top_module_to_paths.is_empty());
+
+        let test_lib = produce(&entryfile, &["db"], &["util"]);
+        let modules = test_lib
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                syn::Item::Mod(item_mod) => Some(item_mod),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].ident, "db");
+        // the content is copied, with the tests stripped
+        let (_, items) = modules[0].content.as_ref().unwrap();
+        assert_eq!(items.len(), 1);
+        assert!(matches!(&items[0], syn::Item::Fn(f) if f.sig.ident == "query"));
+        let uses = test_lib
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                syn::Item::Use(item_use) => Some(item_use.tree.to_token_stream().to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(uses, ["__layer_test :: util"]);
+    }
+
+    #[test]
+    fn test_macro_use_extern_crate_is_kept() {
+        let entryfile = resolve(
+            r#"
+            #[macro_use]
+            extern crate log;
+            pub mod db {
+                pub fn query() {
+                    info!("query");
+                }
+            }
+            "#,
+        );
+        let test_lib = produce(&entryfile, &["db"], &[]);
+        let syn::Item::ExternCrate(item_extern) = &test_lib.items[0] else {
+            panic!("expected extern crate first in the test library");
+        };
+        assert_eq!(item_extern.ident, "log");
+        let attrs = item_extern
+            .attrs
+            .iter()
+            .map(|attr| attr.to_token_stream().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(attrs, ["# [allow (unused_imports)]", "# [macro_use]"]);
+    }
+
+    #[test]
+    fn test_extern_crate_renames() {
+        let check = |content: &str| check_extern_crate_names(&syn::parse_file(content).unwrap());
+        assert!(check("extern crate alloc as mem;").is_ok());
+        assert!(check("extern crate std as __layer_test;").is_err());
+        assert!(check("extern crate __layer_test;").is_err());
+
+        let entryfile = resolve(
+            r#"
+            extern crate alloc as mem;
+            pub mod db {
+                pub fn query() -> mem::vec::Vec<u8> {
+                    mem::vec::Vec::new()
+                }
+            }
+            "#,
+        );
+        let test_lib = produce(&entryfile, &["db"], &[]);
+        let syn::Item::ExternCrate(item_extern) = &test_lib.items[0] else {
+            panic!("expected extern crate first in the test library");
+        };
+        assert_eq!(item_extern.ident, "alloc");
+        let (_, rename) = item_extern.rename.as_ref().unwrap();
+        assert_eq!(rename, "mem");
+    }
+
+    /// Resolve the entry file in a temp dir with the module files
+    fn resolve_with_files(name: &str, content: &str, files: &[&str]) -> (EntryFile, PathBuf) {
+        let dir = std::env::temp_dir()
+            .join(format!("layered-crate-{name}-{}", std::process::id()))
+            .normalize()
+            .unwrap();
+        for file in files {
+            cu::fs::write(dir.join(file), "").unwrap();
+        }
+        let entryfile = EntryFile::resolve(content, &dir, None, "2021");
+        let _ = std::fs::remove_dir_all(&dir);
+        (entryfile.unwrap(), dir)
+    }
+
+    fn module_attrs(entryfile: &EntryFile) -> Vec<String> {
+        let Some(syn::Item::Mod(item)) = entryfile.syntax.items.first() else {
+            panic!("expected a module first in the entry file");
+        };
+        item.attrs
+            .iter()
+            .map(|attr| attr.to_token_stream().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_cfg_attr_path_with_default_file() {
+        let (entryfile, dir) = resolve_with_files(
+            "cfg-attr-default",
+            r#"#[cfg_attr(test, path = "mock.rs")] mod net;"#,
+            &["net.rs", "mock.rs"],
+        );
+        let mock = util::to_forward_slashes(&dir.join("mock.rs").into_utf8().unwrap());
+        let net = util::to_forward_slashes(&dir.join("net.rs").into_utf8().unwrap());
+        // no unconditional path, which rustc warns about when the condition is true
+        assert_eq!(
+            module_attrs(&entryfile),
+            [
+                format!("# [cfg_attr (test , path = {mock:?})]"),
+                format!("# [cfg_attr (not (any (test)) , path = {net:?})]"),
+            ]
+        );
+        assert_eq!(entryfile.top_module_to_paths["net"], net);
+    }
+
+    #[test]
+    fn test_cfg_attr_path_without_default_file() {
+        let (entryfile, dir) = resolve_with_files(
+            "cfg-attr-only",
+            r#"#[cfg_attr(unix, path = "unix.rs")] mod os;"#,
+            &["unix.rs"],
+        );
+        let unix = util::to_forward_slashes(&dir.join("unix.rs").into_utf8().unwrap());
+        let os = util::to_forward_slashes(&dir.join("os.rs").into_utf8().unwrap());
+        // the default path is still used when the condition is false, same as the crate
+        assert_eq!(
+            module_attrs(&entryfile),
+            [
+                format!("# [cfg_attr (unix , path = {unix:?})]"),
+                format!("# [cfg_attr (not (any (unix)) , path = {os:?})]"),
+            ]
+        );
+        assert_eq!(entryfile.top_module_to_paths["os"], unix);
+    }
+
+    #[test]
+    fn test_relative_paths_in_module_file() {
+        let dir = std::env::temp_dir()
+            .join(format!("layered-crate-include-{}", std::process::id()))
+            .normalize()
+            .unwrap();
+        let path = dir.join("db.rs");
+        cu::fs::write(
+            &path,
+            r#"
+            #![doc = include_str!("../README.md")]
+            const DATA: &[u8] = include_bytes!("db/data.bin");
+            const ABSOLUTE: &str = include_str!("/data.txt");
+            pub mod inner {
+                #[path = "x_impl.rs"]
+                pub mod x;
+            }
+            "#,
+        )
+        .unwrap();
+        cu::fs::write(dir.join("db/inner/x_impl.rs"), "").unwrap();
+        let file = parse_module_file("crate::db", &path);
+        let _ = std::fs::remove_dir_all(&dir);
+        let output = file.unwrap().to_token_stream().to_string();
+
+        let resolve = |path: &str| {
+            let resolved = util::to_forward_slashes(&dir.join(path).into_utf8().unwrap());
+            format!("{resolved:?}")
+        };
+        assert!(output.contains(&resolve("../README.md")));
+        assert!(output.contains(&resolve("db/data.bin")));
+        assert!(output.contains(r#""/data.txt""#));
+        // inline modules in `db.rs` are in `db/`
+        assert!(output.contains(&resolve("db/inner/x_impl.rs")));
+    }
+}
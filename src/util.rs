@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 
 use cu::pre::*;
 
@@ -8,11 +8,169 @@ pub fn resolve_path(path: impl AsRef<Path>, base_path: &Path) -> cu::Result<Stri
     base_path.join(path).normalize_exists()?.into_utf8()
 }
 
+/// Resolve the path of a module file from a base path, like [`resolve_path`],
+/// but without resolving symlinks, since rustc looks up the sub-modules
+/// next to the symlink and not next to its target. The base path must be absolute.
+///
+/// The path uses `/` as separator on Windows as well (see [`to_forward_slashes`]),
+/// so it can be put in `#[path]` in the generated source as-is
+pub fn resolve_module_path(path: impl AsRef<Path>, base_path: &Path) -> cu::Result<String> {
+    let mut resolved = PathBuf::new();
+    for component in base_path.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            // `..` after a symlink is the parent of the target, so keep it as is
+            Component::ParentDir if resolved.file_name().is_some() && !resolved.is_symlink() => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    resolved.ensure_exists()?;
+    if resolved.is_symlink() {
+        cu::debug!(
+            "module file '{}' is a symlink, not resolving it",
+            resolved.display()
+        );
+    }
+    Ok(to_forward_slashes(&resolved.into_utf8()?))
+}
+
+/// Use `/` as the path separator on Windows. The verbatim prefix (`\\?\`) is removed
+/// when the path can be expressed without it, since `/` is not a separator in verbatim paths.
+/// Other verbatim paths are returned as is.
+///
+/// Other platforms are unchanged, since `\\` is a valid character in file names
+pub fn to_forward_slashes(path: &str) -> String {
+    to_forward_slashes_on(path, cfg!(windows))
+}
+
+fn to_forward_slashes_on(path: &str, is_windows: bool) -> String {
+    if !is_windows {
+        return path.to_string();
+    }
+    let Some(rest) = path.strip_prefix(r"\\?\") else {
+        return path.replace('\\', "/");
+    };
+    if let Some(unc) = rest.strip_prefix(r"UNC\") {
+        return format!("//{}", unc.replace('\\', "/"));
+    }
+    let is_disk = match rest.as_bytes() {
+        [letter, b':'] => letter.is_ascii_alphabetic(),
+        [letter, b':', b'\\', ..] => letter.is_ascii_alphabetic(),
+        _ => false,
+    };
+    if is_disk {
+        return rest.replace('\\', "/");
+    }
+    path.to_string()
+}
+
 /// The generated package name for building the crate by layers
 pub fn test_package_name(name: &str) -> String {
     format!("{name}-layer-test-{}", name.len())
 }
 
+/// The generated package name for building a layer in batch mode
+pub fn batch_test_package_name(test_package_name: &str, layer: &str) -> String {
+    format!("{test_package_name}-{layer}")
+}
+
+/// Insert extra args for cargo (not for the cargo subcommand),
+/// i.e. before `--` if it exists in the args
+pub fn insert_cargo_args(args: &[String], extra: Vec<String>) -> Vec<String> {
+    let mut output = args.to_vec();
+    let index = args.iter().position(|x| x == "--").unwrap_or(args.len());
+    output.splice(index..index, extra);
+    output
+}
+
+/// Find cargo config files that apply when running cargo in the directory
+/// (i.e. `.cargo/config.toml` in the directory and its ancestors)
+pub fn find_cargo_configs(dir: &Path) -> Vec<PathBuf> {
+    let mut configs = vec![];
+    let mut current = Some(dir);
+    while let Some(dir) = current {
+        for name in ["config.toml", "config"] {
+            let path = dir.join(".cargo").join(name);
+            if path.exists() {
+                configs.push(path);
+            }
+        }
+        current = dir.parent();
+    }
+    configs
+}
+
+/// Add the color flag to the cargo args, if not already specified
+pub fn add_color_flag_if_missing(cargo_args: &mut Vec<String>) {
+    if !cargo_args.iter().any(|arg| arg.starts_with("--color")) {
+        cargo_args.push(cu::color_flag_eq().to_string());
+    }
+}
+
+/// Check if cargo will run in offline mode with the args
+pub fn is_offline(cargo_args: &[String]) -> bool {
+    if cargo_args
+        .iter()
+        .take_while(|x| *x != "--")
+        .any(|x| x == "--offline" || x == "--frozen")
+    {
+        return true;
+    }
+    matches!(
+        std::env::var("CARGO_NET_OFFLINE").as_deref(),
+        Ok("true" | "1")
+    )
+}
+
+/// Check if cargo requires the lock file to be up-to-date with the args
+pub fn is_locked(cargo_args: &[String]) -> bool {
+    cargo_args
+        .iter()
+        .take_while(|x| *x != "--")
+        .any(|x| x == "--locked" || x == "--frozen")
+}
+
+/// Add extra flags to RUSTFLAGS. If `rust_flags` is `None`, the flags are added
+/// to the RUSTFLAGS from the environment
+pub fn extend_rustflags(rust_flags: Option<&str>, extra: &[String]) -> String {
+    let mut output = match rust_flags {
+        Some(x) => x.to_string(),
+        None => std::env::var("RUSTFLAGS").unwrap_or_default(),
+    };
+    for flag in extra {
+        if !output.is_empty() {
+            output.push(' ');
+        }
+        output.push_str(flag);
+    }
+    output
+}
+
+/// Find the nearest cargo config from `dir` that sets `rustflags` in `[build]`
+/// or `[target]`, which cargo ignores if RUSTFLAGS is set
+pub fn find_config_with_rustflags(dir: &Path) -> Option<PathBuf> {
+    find_cargo_configs(dir).into_iter().find(|path| {
+        let Ok(content) = cu::fs::read_string(path) else {
+            return false;
+        };
+        let Ok(config) = toml::parse::<toml::Table>(&content) else {
+            cu::debug!("failed to parse cargo config '{}'", path.display());
+            return false;
+        };
+        let in_build = config
+            .get("build")
+            .and_then(|x| x.get("rustflags"))
+            .is_some();
+        let in_target = config
+            .get("target")
+            .and_then(|x| x.as_table())
+            .is_some_and(|x| x.values().any(|x| x.get("rustflags").is_some()));
+        in_build || in_target
+    })
+}
+
 pub fn add_rustflag_if_missing(flag: &str, rust_flags: &mut String) {
     // currently we only do basic check
     // so -D unused-imports won't get detected, for example
@@ -24,8 +182,86 @@ pub fn add_rustflag_if_missing(flag: &str, rust_flags: &mut String) {
     }
 }
 
-pub fn run_rustfmt(input: String) -> String {
-    match run_rustfmt_internal(&input) {
+/// Produce a unified diff of the lines, with 3 lines of context around the changes.
+/// Returns an empty string if there are no changes
+pub fn unified_diff(old: &str, new: &str) -> String {
+    const CONTEXT: usize = 3;
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    // (tag, index in old, index in new, line)
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((' ', i, j, old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            // removed lines come before added lines, like other diff tools
+            ops.push(('-', i, j, old[i]));
+            i += 1;
+        } else {
+            ops.push(('+', i, j, new[j]));
+            j += 1;
+        }
+    }
+    let changes = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| op.0 != ' ')
+        .map(|(k, _)| k)
+        .collect::<Vec<_>>();
+    let mut output = String::new();
+    let mut k = 0;
+    while k < changes.len() {
+        // changes close to each other are in the same hunk
+        let start = changes[k].saturating_sub(CONTEXT);
+        let mut last = changes[k];
+        while k < changes.len() && changes[k] <= last + 2 * CONTEXT {
+            last = changes[k];
+            k += 1;
+        }
+        let hunk = &ops[start..(last + CONTEXT + 1).min(ops.len())];
+        let old_len = hunk.iter().filter(|op| op.0 != '+').count();
+        let new_len = hunk.iter().filter(|op| op.0 != '-').count();
+        let (_, old_start, new_start, _) = hunk[0];
+        output.push_str(&format!(
+            "@@ -{},{old_len} +{},{new_len} @@\n",
+            old_start + 1,
+            new_start + 1
+        ));
+        for (tag, _, _, line) in hunk {
+            output.push_str(&format!("{tag}{line}\n"));
+        }
+    }
+    output
+}
+
+/// 64-bit FNV-1a hash, which is stable across runs and platforms
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Format the source code of the edition with `rustfmt`, or the format command if specified.
+/// Returns the input unchanged if formatting fails
+pub fn run_rustfmt(input: String, format_cmd: Option<&str>, edition: &str) -> String {
+    match run_rustfmt_internal(&input, format_cmd, edition) {
         Ok(x) => x,
         Err(e) => {
             cu::debug!("rustfmt failed: {e:?}");
@@ -33,10 +269,25 @@ pub fn run_rustfmt(input: String) -> String {
         }
     }
 }
-fn run_rustfmt_internal(input: &str) -> cu::Result<String> {
-    let (child, output) = cu::which("rustfmt")?
+fn run_rustfmt_internal(
+    input: &str,
+    format_cmd: Option<&str>,
+    edition: &str,
+) -> cu::Result<String> {
+    let (program, args) = match format_cmd {
+        Some(format_cmd) => {
+            let mut parts = format_cmd.split_whitespace();
+            let Some(program) = parts.next() else {
+                cu::bail!("format command is empty");
+            };
+            (cu::which(program)?, parts.collect::<Vec<_>>())
+        }
+        None => (cu::which("rustfmt")?, vec![]),
+    };
+    let (child, output) = program
         .command()
-        .args(["--edition", "2024", "--emit", "stdout"])
+        .args(args)
+        .args(["--edition", edition, "--emit", "stdout"])
         .stdin(cu::pio::write(input.as_bytes().to_vec()))
         .stdout(cu::pio::string())
         .stderr_null()
@@ -45,3 +296,48 @@ fn run_rustfmt_internal(input: &str) -> cu::Result<String> {
     let output = output.join()??;
     Ok(output)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows(path: &str) -> String {
+        to_forward_slashes_on(path, true)
+    }
+
+    #[test]
+    fn test_forward_slashes_unix() {
+        let unix = |path| to_forward_slashes_on(path, false);
+        assert_eq!(unix("/home/a/src/lib.rs"), "/home/a/src/lib.rs");
+        // `\` is part of the file name
+        assert_eq!(unix(r"/home/a/src/a\b.rs"), r"/home/a/src/a\b.rs");
+    }
+
+    #[test]
+    fn test_forward_slashes_windows() {
+        assert_eq!(windows("C:/work/a/src/lib.rs"), "C:/work/a/src/lib.rs");
+        assert_eq!(
+            windows(r"C:\work\a\src\db\mod.rs"),
+            "C:/work/a/src/db/mod.rs"
+        );
+        assert_eq!(
+            windows(r"\\server\share\a\src\lib.rs"),
+            "//server/share/a/src/lib.rs"
+        );
+    }
+
+    #[test]
+    fn test_forward_slashes_verbatim() {
+        assert_eq!(windows(r"\\?\C:\work\a\src\lib.rs"), "C:/work/a/src/lib.rs");
+        assert_eq!(windows(r"\\?\C:"), "C:");
+        assert_eq!(
+            windows(r"\\?\UNC\server\share\a\src\lib.rs"),
+            "//server/share/a/src/lib.rs"
+        );
+        // can't be expressed without the verbatim prefix
+        assert_eq!(
+            windows(r"\\?\Volume{1234}\a\lib.rs"),
+            r"\\?\Volume{1234}\a\lib.rs"
+        );
+    }
+}